@@ -1,60 +1,309 @@
-use std::net::{Ipv4Addr, TcpStream};
-use std::io::{self, Read, Write};
-use std::fs::File;
-use std::path::{PathBuf};
+use std::net::{Ipv4Addr, Shutdown, TcpStream};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process;
+use net2::proto::*;
+use net2::resume::fingerprint;
+use net2::sandbox::{create_unique, sanitize_name};
+use sha2::{Digest, Sha256};
 
 const BUFFER_SIZE: usize = 8192;
 
-fn send_file(server_addr: &str, server_port: u16, file_path: &str) -> io::Result<()> {
-    let binding = PathBuf::from(file_path);
-    let file_name = binding
-        .file_name()
-        .unwrap()
+fn utf8_name(name: Option<&std::ffi::OsStr>) -> io::Result<&str> {
+    name.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?
         .to_str()
-        .unwrap();
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "file name isn't UTF-8"))
+}
+
+/// Adds the file at `path`, or every file under the directory, named relative to the directory's
+/// parent so that a directory arrives under its own name.
+fn collect_files(path: &Path, name: String, files: &mut Vec<(PathBuf, String)>) -> io::Result<()> {
+    if !fs::metadata(path)?.is_dir() {
+        files.push((path.to_owned(), name));
+        return Ok(());
+    }
+
+    let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        // Links to directories may lead in circles, links to files are sent as files.
+        if entry.file_type()?.is_symlink() && entry.path().is_dir() {
+            eprintln!("Skipping the link to a directory {}", entry.path().display());
+            continue;
+        }
 
-    let file_name_len:u8 = file_name.len() as u8;
-    let file = File::open(file_path)?;
-    let file_size = file.metadata()?.len();
+        let entry_name = utf8_name(Some(&entry.file_name()))?.to_owned();
+        collect_files(&entry.path(), format!("{}/{}", name, entry_name), files)?;
+    }
 
+    Ok(())
+}
+
+/// Connects and sends `request` if the server speaks our protocol, which the `Status` tells.
+fn open_session(server_addr: &str, server_port: u16, request: u8) -> io::Result<(TcpStream, Status)> {
     let mut stream = TcpStream::connect((server_addr, server_port))?;
+
+    write_hello(&mut stream)?;
+    let status = Status::read_from(&mut stream)?;
+    if status.is_ok() {
+        write_request(&mut stream, request)?;
+    }
+
+    Ok((stream, status))
+}
+
+fn send_files(server_addr: &str, server_port: u16, files: &[(PathBuf, String)]) -> io::Result<Status> {
+    let mut manifest = Manifest { files: Vec::new() };
+    for (path, name) in files {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        manifest.files.push(Header {
+            name: name.clone(),
+            size,
+            fingerprint: fingerprint(&mut file, size)?,
+        });
+    }
+
+    let (mut stream, status) = open_session(server_addr, server_port, REQUEST_PUT)?;
+    if !status.is_ok() {
+        return Ok(status);
+    }
+
+    manifest.write_to(&mut stream)?;
+    let status = Status::read_from(&mut stream)?;
+    if !status.is_ok() {
+        return Ok(status);
+    }
+
+    let mut bytes_sent = 0;
+    for ((path, _), header) in files.iter().zip(&manifest.files) {
+        let mut status = Status::read_from(&mut stream)?;
+        if status.is_ok() {
+            // The server keeps what it got of an interrupted upload of the same file.
+            let offset = read_u64(&mut stream)?;
+            bytes_sent += send_file(&mut stream, path, header, offset)?;
+            status = Status::read_from(&mut stream)?;
+        }
+
+        if status.is_ok() {
+            println!("{}: {}", header.name, status);
+        } else {
+            eprintln!("{}: {}", header.name, status);
+        }
+    }
+
+    // The server takes the end of the stream as the end of the upload.
+    stream.shutdown(Shutdown::Write)?;
+    println!("Total bytes sent: {}, waiting for the server", bytes_sent);
+
+    Status::read_from(&mut stream)
+}
+
+/// Sends the file from `offset` on and its SHA-256, returning the bytes of the file sent.
+fn send_file(stream: &mut TcpStream, path: &Path, header: &Header, offset: u64) -> io::Result<u64> {
+    let mut file = File::open(path)?;
     let mut buffer = vec![0; BUFFER_SIZE];
+    if offset > 0 {
+        println!("Resuming {} after {} of {} bytes", header.name, offset, header.size);
+    }
 
-    // Send file len, file name, size, and contents to the server
-    stream.write_all(&file_name_len.to_le_bytes())?;
-    stream.write_all(file_name.as_bytes())?;
-    stream.write_all(&(file_size).to_le_bytes())?;
+    // The digest covers the whole file, including what the server already has.
+    let mut hasher = Sha256::new();
+    io::copy(&mut (&file).take(offset), &mut hasher)?;
+    file.seek(SeekFrom::Start(offset))?;
 
     let mut bytes_sent = 0;
-    loop {
-        let bytes_read = file.try_clone().expect("failed to reopen file").take(BUFFER_SIZE as u64).read(&mut buffer)?;
+    while offset + bytes_sent < header.size {
+        let remaining = header.size - offset - bytes_sent;
+        let bytes_read = file.try_clone().expect("failed to reopen file").take(remaining.min(BUFFER_SIZE as u64)).read(&mut buffer)?;
         if bytes_read == 0 {
-            break;
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while sending it"));
         }
 
         stream.write_all(&buffer[..bytes_read])?;
+        hasher.update(&buffer[..bytes_read]);
         bytes_sent += bytes_read as u64;
     }
 
-    println!("File sent successfully. Total bytes sent: {}", bytes_sent);
+    let digest = hasher.finalize();
+    stream.write_all(&digest)?;
+    println!("SHA-256 of {}: {}", header.name, to_hex(&digest));
 
-    Ok(())
+    Ok(bytes_sent)
+}
+
+/// Downloads `name` into the current directory, never replacing a file there.
+fn download(server_addr: &str, server_port: u16, name: &str) -> io::Result<Status> {
+    let (mut stream, status) = open_session(server_addr, server_port, REQUEST_GET)?;
+    if !status.is_ok() {
+        return Ok(status);
+    }
+
+    write_name(&mut stream, name)?;
+    stream.flush()?;
+    let status = Status::read_from(&mut stream)?;
+    if !status.is_ok() {
+        return Ok(status);
+    }
+    let file_size = read_u64(&mut stream)?;
+
+    // The name comes from the server, which is trusted no more than clients are by it.
+    let (mut file, path) = create_unique(Path::new("."), &sanitize_name(name))?;
+    let received = receive_file(&mut stream, &mut file, file_size);
+    if received.is_err() {
+        drop(file);
+        fs::remove_file(&path)?;
+    }
+    received?;
+
+    println!("Saved to {}", path.display());
+    Status::read_from(&mut stream)
+}
+
+/// Receives the file and checks it against the SHA-256 that follows.
+fn receive_file(stream: &mut TcpStream, file: &mut File, file_size: u64) -> io::Result<()> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut hasher = Sha256::new();
+    let mut received = 0;
+    while received < file_size {
+        let want = buffer.len().min((file_size - received) as usize);
+        let bytes_read = stream.read(&mut buffer[..want])?;
+        if bytes_read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("received {} of {} bytes", received, file_size),
+            ));
+        }
+
+        file.write_all(&buffer[..bytes_read])?;
+        hasher.update(&buffer[..bytes_read]);
+        received += bytes_read as u64;
+    }
+    println!("Total bytes received: {}", received);
+
+    let mut expected = [0; DIGEST_LEN];
+    stream.read_exact(&mut expected)?;
+    let actual = hasher.finalize();
+    if actual[..] != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the file is corrupted, expected SHA-256 {} but received {}", to_hex(&expected), to_hex(&actual)),
+        ));
+    }
+    println!("SHA-256: {}", to_hex(&actual));
+
+    file.sync_all()
+}
+
+fn list(server_addr: &str, server_port: u16) -> io::Result<Status> {
+    let (mut stream, status) = open_session(server_addr, server_port, REQUEST_LIST)?;
+    if !status.is_ok() {
+        return Ok(status);
+    }
+
+    let status = Status::read_from(&mut stream)?;
+    if !status.is_ok() {
+        return Ok(status);
+    }
+    for entry in Listing::read_from(&mut stream)?.entries {
+        println!("{:>14}  {}  {}", entry.size, format_time(entry.modified), entry.name);
+    }
+
+    Status::read_from(&mut stream)
+}
+
+/// Formats seconds since the Unix epoch as a UTC date and time.
+fn format_time(secs: u64) -> String {
+    // Days to a civil date, from Howard Hinnant's `civil_from_days`.
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year, month, day, time / 3600, time / 60 % 60, time % 60
+    )
+}
+
+/// Exits with 1 unless the server reported success.
+fn finish(result: io::Result<Status>, success: &str, failure: &str) {
+    match result {
+        Ok(status) if status.is_ok() => println!("{}: {}", success, status),
+        Ok(status) => {
+            eprintln!("{}: {}", failure, status);
+            process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("{}: {:?}", failure, err);
+            process::exit(1);
+        }
+    }
 }
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
 
-    if args.len() != 4 {
-        eprintln!("Usage: {} <server_addr> <server_port> <file_path>", args[0]);
-        return;
+    if args.len() < 4 {
+        eprintln!("Usage: {} <server_addr> <server_port> <path>...", args[0]);
+        eprintln!("       {} <server_addr> <server_port> --get <name>", args[0]);
+        eprintln!("       {} <server_addr> <server_port> --list", args[0]);
+        process::exit(2);
     }
 
     let server_addr: &Ipv4Addr = &args[1].parse().expect("Invalid server address");
     let server_port: u16 = args[2].parse().expect("Invalid server port");
-    let file_path: &str = &args[3];
 
-    match send_file(&*server_addr.to_string(), server_port, file_path) {
-        Ok(_) => println!("File sent successfully."),
-        Err(err) => eprintln!("Error sending file: {:?}", err),
+    match (args[3].as_str(), args.len()) {
+        ("--get", 5) => {
+            finish(download(&server_addr.to_string(), server_port, &args[4]), "File downloaded successfully", "Error downloading file");
+            return;
+        }
+        ("--list", 4) => {
+            finish(list(&server_addr.to_string(), server_port), "Listed successfully", "Error listing files");
+            return;
+        }
+        ("--get" | "--list", _) => {
+            eprintln!("Usage: {} <server_addr> <server_port> --get <name> | --list", args[0]);
+            process::exit(2);
+        }
+        _ => {}
+    }
+
+    let mut files = Vec::new();
+    for path in &args[3..] {
+        // `.` and `dir/..` are named after the directory they stand for.
+        let full = match Path::new(path).file_name() {
+            Some(_) => Ok(PathBuf::from(path)),
+            None => fs::canonicalize(path),
+        };
+        let collected = full.and_then(|full| {
+            let name = utf8_name(full.file_name())?.to_owned();
+            collect_files(Path::new(path), name, &mut files)
+        });
+        if let Err(err) = collected {
+            eprintln!("Error reading {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+
+    match send_files(&server_addr.to_string(), server_port, &files) {
+        Ok(status) if status.is_ok() => println!("Files sent successfully: {}", status),
+        Ok(status) => {
+            eprintln!("Server failed to receive the files: {}", status);
+            process::exit(1);
+        }
+        Err(err) => {
+            // Whatever reached the server is kept, running again resumes from there.
+            eprintln!("Error sending files: {:?}", err);
+            process::exit(1);
+        }
     }
 }
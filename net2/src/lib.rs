@@ -0,0 +1,6 @@
+pub mod proto;
+pub mod resume;
+pub mod sandbox;
+
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,460 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+
+/// Opens every session, followed by the protocol version.
+pub const MAGIC: [u8; 4] = *b"NET2";
+pub const VERSION: u8 = 6;
+
+/// Length of the SHA-256 that follows every file sent.
+pub const DIGEST_LEN: usize = 32;
+
+/// Longest file name in bytes of UTF-8.
+pub const MAX_NAME_LEN: usize = 4096;
+/// Most files a single session may send.
+pub const MAX_FILES: u32 = 100_000;
+/// Most files a `Listing` may hold.
+pub const MAX_ENTRIES: u32 = 1_000_000;
+
+pub const STATUS_OK: u8 = 0x0;
+pub const STATUS_SIZE_MISMATCH: u8 = 0x1;
+pub const STATUS_ERROR: u8 = 0x2;
+pub const STATUS_UNSUPPORTED_VERSION: u8 = 0x3;
+pub const STATUS_INVALID_HEADER: u8 = 0x4;
+pub const STATUS_BUSY: u8 = 0x5;
+pub const STATUS_CHECKSUM_MISMATCH: u8 = 0x6;
+pub const STATUS_NOT_FOUND: u8 = 0x7;
+pub const STATUS_UNKNOWN_REQUEST: u8 = 0x8;
+
+/// Uploads files, followed by a `Manifest`.
+pub const REQUEST_PUT: u8 = 0x0;
+/// Downloads a file, followed by its name as `write_name` sends it. The server answers with a
+/// `Status` and, if it's OK, the size as u64 LE, the file and its SHA-256.
+pub const REQUEST_GET: u8 = 0x1;
+/// Lists the files on the server, answered with a `Status` and, if it's OK, a `Listing`.
+pub const REQUEST_LIST: u8 = 0x2;
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn name_too_long(len: usize) -> io::Error {
+    invalid_data(format!(
+        "file name is {} bytes long, at most {} are allowed",
+        len, MAX_NAME_LEN
+    ))
+}
+
+pub fn write_hello(writer: &mut impl Write) -> io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    writer.flush()
+}
+
+/// Reads the peer's protocol version, `None` if it doesn't start with `MAGIC`.
+///
+/// The server answers with a `Status`, then the client says what it wants with a request byte.
+/// Every session ends with a `Status` from the server.
+pub fn read_hello(reader: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut hello = [0; MAGIC.len() + 1];
+    reader.read_exact(&mut hello)?;
+
+    if hello[..MAGIC.len()] != MAGIC {
+        return Ok(None);
+    }
+    Ok(Some(hello[MAGIC.len()]))
+}
+
+/// Announces a file: name length as u16 LE, UTF-8 name, size as u64 LE, fingerprint as u64 LE.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// Path relative to the upload directory, components separated by `/`.
+    pub name: String,
+    pub size: u64,
+    /// Tells a resumed upload from a different file of the same name and size.
+    pub fingerprint: u64,
+}
+
+impl Header {
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        write_name(writer, &self.name)?;
+        writer.write_all(&self.size.to_le_bytes())?;
+        writer.write_all(&self.fingerprint.to_le_bytes())
+    }
+
+    /// Fails with `InvalidData` for names that are too long, empty or not UTF-8.
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+        Ok(Self {
+            name: read_name(reader)?,
+            size: read_u64(reader)?,
+            fingerprint: read_u64(reader)?,
+        })
+    }
+}
+
+fn validate_name(name: &str) -> io::Result<()> {
+    if name.is_empty() {
+        return Err(invalid_data("empty file name"));
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(name_too_long(name.len()));
+    }
+    Ok(())
+}
+
+/// Sends a file name: length as u16 LE, UTF-8 name.
+pub fn write_name(writer: &mut impl Write, name: &str) -> io::Result<()> {
+    validate_name(name)?;
+
+    writer.write_all(&(name.len() as u16).to_le_bytes())?;
+    writer.write_all(name.as_bytes())
+}
+
+/// Fails with `InvalidData` for names that are too long, empty or not UTF-8.
+pub fn read_name(reader: &mut impl Read) -> io::Result<String> {
+    let mut len = [0; 2];
+    reader.read_exact(&mut len)?;
+
+    let len = u16::from_le_bytes(len) as usize;
+    // Checked before allocating the name.
+    if len > MAX_NAME_LEN {
+        return Err(name_too_long(len));
+    }
+
+    let mut name = vec![0; len];
+    reader.read_exact(&mut name)?;
+    let name = String::from_utf8(name).map_err(|_| invalid_data("file name isn't UTF-8"))?;
+    validate_name(&name)?;
+
+    Ok(name)
+}
+
+/// Announces every file of the session: file count as u32 LE and a `Header` for each.
+///
+/// The server answers with a `Status`, then goes through the files in order. For each it sends
+/// a `Status` and, if it's OK, the offset to send from, the client sends the file from there and
+/// its SHA-256 and the server answers with the file's `Status`. After the last file the client
+/// shuts down its side and the server sends a `Status` for the whole session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub files: Vec<Header>,
+}
+
+impl Manifest {
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        if self.files.len() > MAX_FILES as usize {
+            return Err(too_many_files(self.files.len()));
+        }
+
+        writer.write_all(&(self.files.len() as u32).to_le_bytes())?;
+        for header in &self.files {
+            header.write_to(writer)?;
+        }
+        writer.flush()
+    }
+
+    /// Fails with `InvalidData` for too many files or any invalid `Header`.
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+        let mut count = [0; 4];
+        reader.read_exact(&mut count)?;
+
+        let count = u32::from_le_bytes(count);
+        if count > MAX_FILES {
+            return Err(too_many_files(count as usize));
+        }
+
+        let files = (0..count)
+            .map(|_| Header::read_from(reader))
+            .collect::<io::Result<_>>()?;
+        Ok(Self { files })
+    }
+}
+
+fn too_many_files(count: usize) -> io::Error {
+    invalid_data(format!(
+        "{} files announced, at most {} are allowed per session",
+        count, MAX_FILES
+    ))
+}
+
+/// A file on the server: name as `write_name` sends it, size as u64 LE and modification time
+/// as u64 LE seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Path relative to the upload directory, components separated by `/`.
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,
+}
+
+/// The files on the server: file count as u32 LE and an `Entry` for each.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listing {
+    pub entries: Vec<Entry>,
+}
+
+impl Listing {
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        if self.entries.len() > MAX_ENTRIES as usize {
+            return Err(too_many_entries(self.entries.len()));
+        }
+
+        writer.write_all(&(self.entries.len() as u32).to_le_bytes())?;
+        for entry in &self.entries {
+            write_name(writer, &entry.name)?;
+            writer.write_all(&entry.size.to_le_bytes())?;
+            writer.write_all(&entry.modified.to_le_bytes())?;
+        }
+        writer.flush()
+    }
+
+    /// Fails with `InvalidData` for too many entries or any invalid name.
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+        let mut count = [0; 4];
+        reader.read_exact(&mut count)?;
+
+        let count = u32::from_le_bytes(count);
+        if count > MAX_ENTRIES {
+            return Err(too_many_entries(count as usize));
+        }
+
+        let entries = (0..count)
+            .map(|_| {
+                Ok(Entry {
+                    name: read_name(reader)?,
+                    size: read_u64(reader)?,
+                    modified: read_u64(reader)?,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self { entries })
+    }
+}
+
+fn too_many_entries(count: usize) -> io::Error {
+    invalid_data(format!(
+        "{} files listed, at most {} are allowed",
+        count, MAX_ENTRIES
+    ))
+}
+
+pub fn write_request(writer: &mut impl Write, request: u8) -> io::Result<()> {
+    writer.write_all(&[request])?;
+    writer.flush()
+}
+
+pub fn read_request(reader: &mut impl Read) -> io::Result<u8> {
+    let mut request = [0; 1];
+    reader.read_exact(&mut request)?;
+    Ok(request[0])
+}
+
+/// Sends a u64 LE: the offset an upload resumes from, or the size of a download.
+pub fn write_u64(writer: &mut impl Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())?;
+    writer.flush()
+}
+
+pub fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Lowercase hex, as `sha256sum` prints digests.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parses hex digits in pairs, `None` for anything that isn't.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// The server's verdict on a transfer: result code, message length as u16 LE, UTF-8 message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub code: u8,
+    pub message: String,
+}
+
+impl Status {
+    pub fn new(code: u8, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.code == STATUS_OK
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut len = self.message.len().min(u16::MAX as usize);
+        while !self.message.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        writer.write_all(&[self.code])?;
+        writer.write_all(&(len as u16).to_le_bytes())?;
+        writer.write_all(&self.message.as_bytes()[..len])?;
+        writer.flush()
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+        let mut header = [0; 3];
+        reader.read_exact(&mut header)?;
+
+        let len = u16::from_le_bytes([header[1], header[2]]) as usize;
+        let mut message = vec![0; len];
+        reader.read_exact(&mut message)?;
+
+        let message =
+            String::from_utf8(message).map_err(|_| invalid_data("status message isn't UTF-8"))?;
+
+        Ok(Self::new(header[0], message))
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (status {:#x})", self.message, self.code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str) -> Header {
+        Header {
+            name: name.to_owned(),
+            size: 1 << 40,
+            fingerprint: 0x0123_4567_89ab_cdef,
+        }
+    }
+
+    #[test]
+    fn hello_round_trips() {
+        let mut buf = Vec::new();
+        write_hello(&mut buf).unwrap();
+
+        assert_eq!(read_hello(&mut &buf[..]).unwrap(), Some(VERSION));
+    }
+
+    #[test]
+    fn tells_wrong_magic_from_other_versions() {
+        assert_eq!(read_hello(&mut &b"NET2\x05"[..]).unwrap(), Some(5));
+        assert_eq!(read_hello(&mut &b"NET1\x06"[..]).unwrap(), None);
+        assert_eq!(read_hello(&mut &b"GET /"[..]).unwrap(), None);
+        assert_eq!(
+            read_hello(&mut &b"NET2"[..]).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn manifest_round_trips() {
+        let manifest = Manifest {
+            files: vec![
+                header("a.txt"),
+                header("dir/отчёт.pdf"),
+                header(&"x".repeat(MAX_NAME_LEN)),
+            ],
+        };
+        let mut buf = Vec::new();
+        manifest.write_to(&mut buf).unwrap();
+
+        let mut reader = &buf[..];
+        assert_eq!(Manifest::read_from(&mut reader).unwrap(), manifest);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn listing_and_status_round_trip() {
+        let listing = Listing {
+            entries: vec![Entry {
+                name: "dir/a.txt".to_owned(),
+                size: 6,
+                modified: 1_700_000_000,
+            }],
+        };
+        let status = Status::new(STATUS_CHECKSUM_MISMATCH, "the file is corrupted");
+
+        let mut buf = Vec::new();
+        listing.write_to(&mut buf).unwrap();
+        status.write_to(&mut buf).unwrap();
+
+        let mut reader = &buf[..];
+        assert_eq!(Listing::read_from(&mut reader).unwrap(), listing);
+        assert_eq!(Status::read_from(&mut reader).unwrap(), status);
+    }
+
+    #[test]
+    fn rejects_oversized_names_before_reading_them() {
+        let mut buf = ((MAX_NAME_LEN + 1) as u16).to_le_bytes().to_vec();
+        buf.extend_from_slice(&[b'x'; 16]);
+
+        let err = read_name(&mut &buf[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = header(&"x".repeat(MAX_NAME_LEN + 1))
+            .write_to(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_empty_and_non_utf8_names() {
+        for buf in [&b"\x00\x00"[..], b"\x02\x00\xff\xfe"] {
+            let err = read_name(&mut &buf[..]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", buf);
+        }
+        assert!(write_name(&mut Vec::new(), "").is_err());
+    }
+
+    #[test]
+    fn rejects_too_many_files() {
+        let buf = (MAX_FILES + 1).to_le_bytes();
+
+        let err = Manifest::read_from(&mut &buf[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_too_many_listed_files_before_reading_them() {
+        let buf = u32::MAX.to_le_bytes();
+
+        let err = Listing::read_from(&mut &buf[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncates_long_status_messages_at_a_character() {
+        let status = Status::new(STATUS_ERROR, "ж".repeat(u16::MAX as usize));
+        let mut buf = Vec::new();
+        status.write_to(&mut buf).unwrap();
+
+        let read = Status::read_from(&mut &buf[..]).unwrap();
+        assert_eq!(read.message.len(), u16::MAX as usize - 1);
+        assert!(status.message.starts_with(&read.message));
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        let bytes = [0x00, 0x7f, 0xab, 0xff];
+
+        assert_eq!(to_hex(&bytes), "007fabff");
+        assert_eq!(from_hex("007FABff"), Some(bytes.to_vec()));
+        for hex in ["0", "0g", "+1", "ж1"] {
+            assert_eq!(from_hex(hex), None, "{}", hex);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Resumable uploads: the client's fingerprint of a file and the server's partial files.
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+/// Bytes hashed at the start, in the middle and at the end of a file.
+const SAMPLE_LEN: u64 = 64 * 1024;
+
+pub const PARTIAL_SUFFIX: &str = ".part";
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// Identifies a file's content by its size and samples of it, reading all of a terabyte
+/// to find out where to resume would take about as long as sending it.
+///
+/// Leaves `reader` at the start.
+pub fn fingerprint<R: Read + Seek>(reader: &mut R, size: u64) -> io::Result<u64> {
+    let mut hash = fnv1a(FNV_OFFSET, &size.to_le_bytes());
+    let mut sample = vec![0; SAMPLE_LEN.min(size) as usize];
+
+    let last = size - sample.len() as u64;
+    for start in [0, last / 2, last] {
+        reader.seek(SeekFrom::Start(start))?;
+        reader.read_exact(&mut sample)?;
+        hash = fnv1a(hash, &sample);
+    }
+
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(hash)
+}
+
+/// Name of the partial file for an upload, the same for every attempt at the same file.
+pub fn partial_name(name: &str, size: u64, fingerprint: u64) -> String {
+    let hash = fnv1a(FNV_OFFSET, name.as_bytes());
+    let hash = fnv1a(hash, &size.to_le_bytes());
+    let hash = fnv1a(hash, &fingerprint.to_le_bytes());
+
+    format!("{:016x}{}", hash, PARTIAL_SUFFIX)
+}
+
+/// Removes the partial files in `dir` that weren't written to for `ttl`, returning how many.
+pub fn expire(dir: &Path, ttl: Duration) -> io::Result<usize> {
+    let now = SystemTime::now();
+    let mut expired = 0;
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry
+            .file_name()
+            .to_string_lossy()
+            .ends_with(PARTIAL_SUFFIX)
+        {
+            continue;
+        }
+
+        let modified = entry.metadata()?.modified()?;
+        if now.duration_since(modified).unwrap_or_default() > ttl {
+            fs::remove_file(entry.path())?;
+            expired += 1;
+        }
+    }
+
+    Ok(expired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use std::fs::File;
+    use std::io::Cursor;
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn fingerprint_of(data: &[u8]) -> u64 {
+        fingerprint(&mut Cursor::new(data), data.len() as u64).unwrap()
+    }
+
+    #[test]
+    fn fingerprint_is_stable_and_rewinds() {
+        let data = content(3 * SAMPLE_LEN as usize + 17);
+        let mut reader = Cursor::new(&data);
+        reader.set_position(42);
+
+        let first = fingerprint(&mut reader, data.len() as u64).unwrap();
+        assert_eq!(reader.position(), 0);
+        assert_eq!(fingerprint(&mut reader, data.len() as u64).unwrap(), first);
+        assert_eq!(fingerprint_of(&data), first);
+    }
+
+    #[test]
+    fn fingerprint_changes_with_sampled_bytes_and_size() {
+        let data = content(3 * SAMPLE_LEN as usize + 17);
+        let base = fingerprint_of(&data);
+        let last = data.len() - SAMPLE_LEN as usize;
+
+        for at in [0, last / 2, data.len() - 1] {
+            let mut changed = data.clone();
+            changed[at] ^= 1;
+            assert_ne!(fingerprint_of(&changed), base, "byte {}", at);
+        }
+        assert_ne!(fingerprint_of(&data[..data.len() - 1]), base);
+        assert_ne!(fingerprint_of(&[]), fingerprint_of(&[0]));
+    }
+
+    #[test]
+    fn partial_name_depends_on_every_part() {
+        let name = partial_name("a.txt", 6, 1);
+
+        assert_eq!(partial_name("a.txt", 6, 1), name);
+        assert!(name.ends_with(PARTIAL_SUFFIX));
+        assert_ne!(partial_name("b.txt", 6, 1), name);
+        assert_ne!(partial_name("a.txt", 7, 1), name);
+        assert_ne!(partial_name("a.txt", 6, 2), name);
+    }
+
+    #[test]
+    fn expires_stale_partial_files_only() {
+        let dir = temp_dir("expire");
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        for name in ["stale.part", "stale.txt"] {
+            File::create(dir.join(name))
+                .unwrap()
+                .set_modified(hour_ago)
+                .unwrap();
+        }
+        fs::write(dir.join("fresh.part"), b"data").unwrap();
+
+        assert_eq!(expire(&dir, Duration::from_secs(60)).unwrap(), 1);
+        assert!(!dir.join("stale.part").exists());
+        assert!(dir.join("stale.txt").exists());
+        assert!(dir.join("fresh.part").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
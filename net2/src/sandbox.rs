@@ -0,0 +1,272 @@
+//! Keeps client-supplied names inside the directory the server writes to.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Longest file name most file systems accept, in bytes.
+const MAX_NAME_LEN: usize = 255;
+/// Longer extensions are treated as part of the name when it has to be shortened.
+const MAX_EXTENSION_LEN: usize = 32;
+const FALLBACK_NAME: &str = "file";
+
+/// Device names Windows reserves in every directory, with or without an extension.
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turns a client-supplied name into a single path component that is safe to create.
+///
+/// Only the last component of a path is kept, `.` and `..` never survive, control characters
+/// are dropped, characters Windows forbids are replaced, reserved device names get a leading
+/// underscore and overlong names are shortened, keeping the extension.
+pub fn sanitize_name(name: &str) -> String {
+    let component = name
+        .split(['/', '\\'])
+        .rev()
+        .find(|component| !matches!(*component, "" | "." | ".."))
+        .unwrap_or_default();
+
+    let cleaned: String = component
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+
+    // Windows drops trailing dots and spaces, which would turn `..` back into a parent.
+    let cleaned = cleaned.trim_end_matches(['.', ' ']);
+    if cleaned.is_empty() {
+        return FALLBACK_NAME.to_owned();
+    }
+
+    // `CON.tar.gz` is as much the console as `CON` is.
+    let base = cleaned.split('.').next().unwrap_or_default();
+    let cleaned = if RESERVED
+        .iter()
+        .any(|reserved| base.eq_ignore_ascii_case(reserved))
+    {
+        format!("_{}", cleaned)
+    } else {
+        cleaned.to_owned()
+    };
+
+    let (stem, extension) = split_extension(&cleaned);
+    fit(stem, extension, "")
+}
+
+/// Turns a client-supplied relative path into one that stays inside the directory it's joined
+/// to.
+///
+/// Every component goes through `sanitize_name`, empty, `.` and `..` components are dropped
+/// rather than resolved.
+pub fn sanitize_path(path: &str) -> PathBuf {
+    let sanitized: PathBuf = path
+        .split(['/', '\\'])
+        .filter(|component| !matches!(*component, "" | "." | ".."))
+        .map(sanitize_name)
+        .collect();
+
+    if sanitized.as_os_str().is_empty() {
+        return PathBuf::from(FALLBACK_NAME);
+    }
+    sanitized
+}
+
+/// Creates `name` in `dir`, or the first free `name (n).ext` if it's taken, never replacing
+/// an existing file.
+pub fn create_unique(dir: &Path, name: &str) -> io::Result<(File, PathBuf)> {
+    let (stem, extension) = split_extension(name);
+
+    for n in 0u32.. {
+        let candidate = match n {
+            0 => fit(stem, extension, ""),
+            n => fit(stem, extension, &format!(" ({})", n)),
+        };
+        let path = dir.join(candidate);
+
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "no free name left",
+    ))
+}
+
+/// Splits `name.ext` at the last dot, names starting with their only dot have no extension.
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= MAX_EXTENSION_LEN => name.split_at(dot),
+        _ => (name, ""),
+    }
+}
+
+/// Joins the parts, shortening `stem` at a character boundary to stay within `MAX_NAME_LEN`.
+fn fit(stem: &str, extension: &str, suffix: &str) -> String {
+    let mut len = MAX_NAME_LEN
+        .saturating_sub(extension.len() + suffix.len())
+        .min(stem.len());
+    while !stem.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    format!("{}{}{}", &stem[..len], suffix, extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use std::fs;
+
+    #[test]
+    fn keeps_plain_names() {
+        assert_eq!(sanitize_name("film.mkv"), "film.mkv");
+        assert_eq!(sanitize_name(".bashrc"), ".bashrc");
+        assert_eq!(sanitize_name("отчёт 2024.pdf"), "отчёт 2024.pdf");
+    }
+
+    #[test]
+    fn strips_directories() {
+        assert_eq!(sanitize_name("../../etc/x"), "x");
+        assert_eq!(sanitize_name("/etc/passwd"), "passwd");
+        assert_eq!(sanitize_name("..\\..\\Windows\\win.ini"), "win.ini");
+        assert_eq!(sanitize_name("C:\\evil.exe"), "evil.exe");
+        assert_eq!(sanitize_name("dir/../.."), "dir");
+        assert_eq!(sanitize_name("secret/"), "secret");
+    }
+
+    #[test]
+    fn never_yields_dot_names() {
+        for name in ["", ".", "..", "../..", "/", "\\", "...", ". .", "\0"] {
+            assert_eq!(sanitize_name(name), FALLBACK_NAME, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn drops_control_characters() {
+        assert_eq!(sanitize_name("a\0b.txt"), "ab.txt");
+        assert_eq!(sanitize_name("line\nbreak\r.txt"), "linebreak.txt");
+        assert_eq!(sanitize_name("..\0/x"), "x");
+    }
+
+    #[test]
+    fn replaces_forbidden_characters() {
+        assert_eq!(sanitize_name("a<b>c:d\"e|f?g*h"), "a_b_c_d_e_f_g_h");
+        assert_eq!(sanitize_name("C:evil"), "C_evil");
+    }
+
+    #[test]
+    fn escapes_reserved_names() {
+        assert_eq!(sanitize_name("CON"), "_CON");
+        assert_eq!(sanitize_name("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize_name("Com1.tar"), "_Com1.tar");
+        assert_eq!(sanitize_name("aux.tar.gz"), "_aux.tar.gz");
+        assert_eq!(sanitize_name("CONSOLE"), "CONSOLE");
+        assert_eq!(sanitize_name("trailing. . "), "trailing");
+    }
+
+    #[test]
+    fn shortens_long_names() {
+        let name = format!("{}.tar", "ж".repeat(300));
+        let sanitized = sanitize_name(&name);
+
+        assert!(sanitized.len() <= MAX_NAME_LEN);
+        assert!(sanitized.ends_with("ж.tar"));
+    }
+
+    #[test]
+    fn keeps_relative_trees() {
+        assert_eq!(sanitize_path("out/bin/app"), Path::new("out/bin/app"));
+        assert_eq!(sanitize_path("out\\lib\\app.dll"), Path::new("out/lib/app.dll"));
+        assert_eq!(sanitize_path("./out//a.txt"), Path::new("out/a.txt"));
+        assert_eq!(sanitize_path("out/CON/a?.txt"), Path::new("out/_CON/a_.txt"));
+    }
+
+    #[test]
+    fn paths_never_leave_the_directory() {
+        assert_eq!(sanitize_path("../../etc/passwd"), Path::new("etc/passwd"));
+        assert_eq!(sanitize_path("/etc/passwd"), Path::new("etc/passwd"));
+        assert_eq!(sanitize_path("a/../../b"), Path::new("a/b"));
+        assert_eq!(sanitize_path("C:\\Windows"), Path::new("C_/Windows"));
+
+        for path in ["", "/", "..", "../..", "./.", "\\"] {
+            assert_eq!(sanitize_path(path), Path::new(FALLBACK_NAME), "{:?}", path);
+        }
+        for path in ["a/b", "../a", "/a/b/c", "x/./../y"] {
+            let sanitized = sanitize_path(path);
+            assert!(sanitized.is_relative(), "{:?}", path);
+            assert!(
+                sanitized
+                    .components()
+                    .all(|component| matches!(component, std::path::Component::Normal(_))),
+                "{:?}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn stays_inside_the_directory() {
+        let dir = temp_dir("inside");
+
+        for name in ["../escape", "/tmp/escape", "..", "a/../../escape", "CON"] {
+            let (_, path) = create_unique(&dir, &sanitize_name(name)).unwrap();
+            assert_eq!(path.parent(), Some(dir.as_path()), "{:?}", name);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resolves_collisions() {
+        let dir = temp_dir("collisions");
+        fs::write(dir.join("report.pdf"), "original").unwrap();
+
+        let names: Vec<_> = (0..3)
+            .map(|_| create_unique(&dir, "report.pdf").unwrap().1)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                dir.join("report (1).pdf"),
+                dir.join("report (2).pdf"),
+                dir.join("report (3).pdf")
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("report.pdf")).unwrap(),
+            "original"
+        );
+
+        let (_, path) = create_unique(&dir, ".bashrc").unwrap();
+        assert_eq!(path, dir.join(".bashrc"));
+        let (_, path) = create_unique(&dir, ".bashrc").unwrap();
+        assert_eq!(path, dir.join(".bashrc (1)"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn collisions_keep_long_names_short() {
+        let dir = temp_dir("long");
+        let name = sanitize_name(&format!("{}.bin", "x".repeat(300)));
+
+        create_unique(&dir, &name).unwrap();
+        let (_, path) = create_unique(&dir, &name).unwrap();
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+
+        assert!(file_name.len() <= MAX_NAME_LEN);
+        assert!(file_name.ends_with(" (1).bin"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,88 +1,517 @@
-use std::fs::{create_dir_all, File};
+use std::collections::BTreeSet;
+use std::fs::{self, create_dir_all, File, OpenOptions};
 use std::net::{TcpListener, TcpStream, Ipv4Addr};
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::{io, thread};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use net2::proto::*;
+use net2::resume::{expire, partial_name};
+use net2::sandbox::{create_unique, sanitize_path};
+use sha2::{Digest, Sha256};
 
 const BUFFER_SIZE: usize = 8192;
 const PRINT_INTERVAL: Duration = Duration::from_secs(3);
 const OUTPUT_DIR: &str = "./received_files/";
+/// Unfinished uploads, in a directory of the output directory created before any upload.
+const PARTIAL_DIR_NAME: &str = ".partial";
+/// How long an unfinished upload is kept for the client to resume it.
+const PARTIAL_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Digests of the received files, in a directory of the output directory mirroring its tree so
+/// that they never take a name a client could upload.
+const CHECKSUM_DIR_NAME: &str = ".checksums";
+const CHECKSUM_SUFFIX: &str = ".sha256";
+/// Directories of the output directory that aren't part of the uploaded tree.
+const RESERVED_DIR_NAMES: [&str; 2] = [PARTIAL_DIR_NAME, CHECKSUM_DIR_NAME];
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long a client may take to send a file's checksum after its last byte, and to shut down its
+/// side after the last file.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Partial files being written to, two clients appending to the same one would garble it.
+static ACTIVE: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// A client's hold on a partial file, released when dropped.
+struct Claim(String);
+
+impl Claim {
+    fn new(partial: &str) -> Option<Self> {
+        let mut active = ACTIVE.lock().unwrap();
+        active.insert(partial.to_owned()).then(|| Claim(partial.to_owned()))
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// A client's socket, a trait so that tests can stand in for it.
+trait Connection: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
 
 struct ClientData {
-    total_bytes_received: u64,
+    /// Bytes of files received or sent during the session.
+    total_bytes: u64,
     start_time: Instant,
+    prev_bytes: u64,
+    next_print: Duration,
 }
 
-fn read_u8_from_stream(stream: &mut TcpStream) -> io::Result<u8> {
-    let mut buffer = [0; 1];
-    stream.read_exact(&mut buffer)?;
-    Ok(buffer[0])
+impl ClientData {
+    fn new() -> Self {
+        Self {
+            total_bytes: 0,
+            start_time: Instant::now(),
+            prev_bytes: 0,
+            next_print: PRINT_INTERVAL,
+        }
+    }
+
+    /// Prints speed information every PRINT_INTERVAL seconds, with the progress of the current file.
+    fn report(&mut self, client_id: u32, progress: f64) {
+        if self.start_time.elapsed() < self.next_print {
+            return;
+        }
+
+        let elapsed_secs = self.start_time.elapsed().as_secs() as f64;
+        let average_speed = self.total_bytes as f64 / elapsed_secs;
+        let instant_speed = (self.total_bytes - self.prev_bytes) as f64 / PRINT_INTERVAL.as_secs() as f64;
+        println!(
+            "Client {}: Average speed: {} bytes/s & instant speed: {} bytes/s & {:.2}%",
+            client_id, average_speed, instant_speed, progress * 100.0);
+
+        self.prev_bytes = self.total_bytes;
+        self.next_print += PRINT_INTERVAL;
+    }
 }
 
-fn read_u64_from_stream(stream: &mut TcpStream) -> io::Result<u64> {
-    let mut buffer = [0; 8];
-    stream.read_exact(&mut buffer)?;
-    Ok(u64::from_le_bytes(buffer))
+/// Serves one session with `root` as the upload directory.
+fn handle_client(stream: &mut impl Connection, root: &Path, client_id: u32) {
+    let status = match serve(stream, root, client_id) {
+        Ok(status) => status,
+        Err(err) => {
+            eprintln!("Error serving client {}: {}", client_id, err);
+            Status::new(STATUS_ERROR, err.to_string())
+        }
+    };
+
+    if let Err(err) = status.write_to(stream) {
+        eprintln!("Error sending status to client {}: {}", client_id, err);
+    }
 }
 
-fn handle_client(mut stream: TcpStream, client_id: u32) {
-    let mut buffer = [0; BUFFER_SIZE];
-    let mut client_data = ClientData {
-        total_bytes_received: 0,
-        start_time: Instant::now(),
+fn serve(stream: &mut impl Connection, root: &Path, client_id: u32) -> io::Result<Status> {
+    match read_hello(stream)? {
+        Some(VERSION) => Status::new(STATUS_OK, "ready").write_to(stream)?,
+        Some(version) => {
+            println!("Client {} speaks unsupported protocol version {}", client_id, version);
+            return Ok(Status::new(
+                STATUS_UNSUPPORTED_VERSION,
+                format!("protocol version {} isn't supported, expected {}", version, VERSION),
+            ));
+        }
+        None => {
+            println!("Client {} doesn't speak protocol version {}", client_id, VERSION);
+            return Ok(Status::new(
+                STATUS_UNSUPPORTED_VERSION,
+                format!("unknown protocol, expected version {}", VERSION),
+            ));
+        }
+    }
+
+    let mut client_data = ClientData::new();
+    match read_request(stream)? {
+        REQUEST_PUT => receive_files(stream, root, client_id, &mut client_data),
+        REQUEST_GET => send_file(stream, root, client_id, &mut client_data),
+        REQUEST_LIST => send_listing(stream, root, client_id),
+        request => {
+            println!("Client {} sent an unknown request {:#x}", client_id, request);
+            Ok(Status::new(
+                STATUS_UNKNOWN_REQUEST,
+                format!("unknown request {:#x}", request),
+            ))
+        }
+    }
+}
+
+fn receive_files(stream: &mut impl Connection, root: &Path, client_id: u32, client_data: &mut ClientData) -> io::Result<Status> {
+    let manifest = match Manifest::read_from(stream) {
+        Ok(manifest) => manifest,
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+            println!("Invalid manifest from client {}: {}", client_id, err);
+            return Ok(Status::new(STATUS_INVALID_HEADER, err.to_string()));
+        }
+        Err(err) => return Err(err),
     };
+    let count = manifest.files.len();
+    println!("Client {} sends {} files", client_id, count);
+    Status::new(STATUS_OK, format!("ready for {} files", count)).write_to(stream)?;
+
+    let mut failed = 0;
+    for header in &manifest.files {
+        if !receive_file(stream, root, client_id, header, client_data)?.is_ok() {
+            failed += 1;
+        }
+    }
 
-    let name_len = read_u8_from_stream(&mut stream).expect("failed to read len of name of the file");
-    let mut file_name = vec![0u8; name_len as usize];
-    stream.read_exact(&mut file_name).expect("failed to read file name");
-    let name = String::from_utf8(file_name).expect("failed to read file name");
-    println!("File name: {}", name);
-    let output_dir = format!("{}{}", OUTPUT_DIR, client_id);
-    create_dir_all(&output_dir).expect("Failed to create output directory");
-    let file_size = read_u64_from_stream(&mut stream).expect("failed to read file size");
-
-    let mut file = match File::create(format!("{}/{}", output_dir, name)) {
-        Ok(file) => file,
+    // The client shuts down its side after the last file, anything else is an overrun.
+    stream.set_read_timeout(Some(FINISH_TIMEOUT))?;
+    match stream.read(&mut [0; 1]) {
+        Ok(0) => {}
+        Ok(_) => {
+            println!("Client {} sent more than its manifest announced", client_id);
+            return Ok(Status::new(
+                STATUS_SIZE_MISMATCH,
+                "received more than the manifest announced",
+            ));
+        }
+        Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+            println!("Client {} didn't finish its upload", client_id);
+            return Ok(Status::new(
+                STATUS_ERROR,
+                "expected the end of the upload after the last file",
+            ));
+        }
+        Err(err) => return Err(err),
+    }
+
+    if failed > 0 {
+        println!("{} of {} files from client {} failed", failed, count, client_id);
+        return Ok(Status::new(STATUS_ERROR, format!("{} of {} files failed", failed, count)));
+    }
+    println!("All {} files received successfully for client {}", count, client_id);
+    Ok(Status::new(STATUS_OK, format!("{} files received", count)))
+}
+
+/// Receives one file of the manifest and sends its `Status`, `Err` when the session can't go on.
+fn receive_file(stream: &mut impl Connection, root: &Path, client_id: u32, header: &Header, client_data: &mut ClientData) -> io::Result<Status> {
+    let file_size = header.size;
+    println!("File name: {}", header.name);
+    let relative = upload_path(&header.name);
+    let dir = root.join(relative.parent().unwrap_or(Path::new("")));
+    let name = relative.file_name().unwrap_or_default().to_string_lossy();
+
+    let partial = partial_name(&relative.to_string_lossy(), file_size, header.fingerprint);
+    let Some(_claim) = Claim::new(&partial) else {
+        println!("Client {} uploads a file that is already being uploaded", client_id);
+        return reply(stream, Status::new(STATUS_BUSY, "the file is being uploaded by another client"));
+    };
+    let partial_path = root.join(PARTIAL_DIR_NAME).join(&partial);
+
+    // Failing before the file is sent keeps the session going.
+    let (mut file, offset, mut hasher) = match create_dir_all(&dir).and_then(|()| open_partial(&partial_path, file_size)) {
+        Ok(opened) => opened,
         Err(err) => {
-            eprintln!("Error creating file for client {}: {}", client_id, err);
-            return;
+            eprintln!("Error preparing {} for client {}: {}", relative.display(), client_id, err);
+            return reply(stream, Status::new(STATUS_ERROR, err.to_string()));
         }
     };
-    let mut prev_bytes_received: u64 = 0;
-    let mut next_print = PRINT_INTERVAL;
-    loop {
-        match stream.read(&mut buffer) {
-            Ok(bytes_read) if bytes_read > 0 => {
-                client_data.total_bytes_received += bytes_read as u64;
-                file.write_all(&buffer[..bytes_read]).unwrap();  // Write to file
-            }
-            Ok(0) => {
-                // End of stream (client disconnected)
-                println!("Client {} disconnected", client_id);
-                break;
-            }
-            Err(err) => {
-                eprintln!("Error reading from client {}: {}", client_id, err);
-                break;
+    if offset > 0 {
+        println!("Client {} resumes after {} of {} bytes", client_id, offset, file_size);
+        Status::new(STATUS_OK, format!("resuming after {} bytes", offset)).write_to(stream)?;
+    } else {
+        Status::new(STATUS_OK, "ready").write_to(stream)?;
+    }
+    write_u64(stream, offset)?;
+
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut received = offset;
+    while received < file_size {
+        // Never read past the announced size, whatever follows isn't part of the file.
+        let want = buffer.len().min((file_size - received) as usize);
+        let bytes_read = stream.read(&mut buffer[..want])?;
+        if bytes_read == 0 {
+            // End of stream (client disconnected), the partial file stays for it to resume.
+            println!("Client {} disconnected", client_id);
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("received {} of {} bytes of {}", received, file_size, header.name),
+            ));
+        }
+        received += bytes_read as u64;
+        client_data.total_bytes += bytes_read as u64;
+        file.write_all(&buffer[..bytes_read])?;  // Write to file
+        hasher.update(&buffer[..bytes_read]);
+
+        client_data.report(client_id, received as f64 / file_size as f64);
+    }
+    println!("{} bytes from {} recieved", received, file_size);
+
+    stream.set_read_timeout(Some(FINISH_TIMEOUT))?;
+    let mut expected = [0; DIGEST_LEN];
+    let read = stream.read_exact(&mut expected);
+    stream.set_read_timeout(None)?;
+    if let Err(err) = read {
+        // The file is complete, a resumed upload only has to send the digest.
+        println!("Client {} didn't send the checksum", client_id);
+        return Err(io::Error::new(
+            err.kind(),
+            format!("expected the checksum of {}: {}", header.name, err),
+        ));
+    }
+
+    let actual = hasher.finalize();
+    if actual[..] != expected {
+        // Resuming would only keep the damage, the next attempt starts over.
+        println!("Checksum mismatch for client {}", client_id);
+        fs::remove_file(&partial_path)?;
+        return reply(stream, Status::new(
+            STATUS_CHECKSUM_MISMATCH,
+            format!("the file is corrupted, sent SHA-256 {} but received {}", to_hex(&expected), to_hex(&actual)),
+        ));
+    }
+
+    let path = match finish(file, &partial_path, root, &dir, &name, &actual) {
+        Ok(path) => path,
+        Err(err) => {
+            eprintln!("Error saving {} for client {}: {}", relative.display(), client_id, err);
+            return reply(stream, Status::new(STATUS_ERROR, err.to_string()));
+        }
+    };
+
+    println!("File received successfully for client {}, saved to {}", client_id, path.display());
+    reply(stream, Status::new(
+        STATUS_OK,
+        format!("{} bytes received, SHA-256 {}", file_size, to_hex(&actual)),
+    ))
+}
+
+/// Sends a file from the upload directory, with the digest saved when it was uploaded.
+fn send_file(stream: &mut impl Connection, root: &Path, client_id: u32, client_data: &mut ClientData) -> io::Result<Status> {
+    let name = match read_name(stream) {
+        Ok(name) => name,
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+            println!("Invalid file name from client {}: {}", client_id, err);
+            return Ok(Status::new(STATUS_INVALID_HEADER, err.to_string()));
+        }
+        Err(err) => return Err(err),
+    };
+    println!("Client {} downloads {}", client_id, name);
+
+    // The same sandbox as uploads, nothing outside the upload directory, partials or checksums
+    // can be read, not even through a link.
+    let root = fs::canonicalize(root)?;
+    let not_found = || Ok(Status::new(STATUS_NOT_FOUND, format!("no file named {}", name)));
+    let path = match resolve(&root, &root.join(upload_path(&name))) {
+        Ok(Some(path)) => path,
+        Ok(None) => {
+            println!("Client {} asked for {}, which leads out of the upload directory", client_id, name);
+            return not_found();
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return not_found(),
+        Err(err) => return Err(err),
+    };
+    let file = File::open(&path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return not_found();
+    }
+
+    let file_size = metadata.len();
+    let stored = read_checksum(&root, &path);
+    Status::new(STATUS_OK, format!("sending {} bytes", file_size)).write_to(stream)?;
+    write_u64(stream, file_size)?;
+
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut hasher = Sha256::new();
+    let mut sent = 0;
+    while sent < file_size {
+        let want = buffer.len().min((file_size - sent) as usize);
+        let bytes_read = (&file).read(&mut buffer[..want])?;
+        if bytes_read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} shrank while sending it", path.display()),
+            ));
+        }
+        stream.write_all(&buffer[..bytes_read])?;
+        hasher.update(&buffer[..bytes_read]);
+        sent += bytes_read as u64;
+        client_data.total_bytes += bytes_read as u64;
+
+        client_data.report(client_id, sent as f64 / file_size as f64);
+    }
+    println!("{} bytes from {} sent", sent, file_size);
+
+    // The digest from the upload lets the client catch the file rotting on the server's disk too.
+    let actual = hasher.finalize();
+    let digest = stored.unwrap_or_else(|| actual.to_vec());
+    stream.write_all(&digest)?;
+
+    if digest[..] != actual[..] {
+        println!("{} doesn't match its checksum", path.display());
+        return Ok(Status::new(
+            STATUS_CHECKSUM_MISMATCH,
+            format!("the stored file is corrupted, its SHA-256 is {} instead of {}", to_hex(&actual), to_hex(&digest)),
+        ));
+    }
+
+    println!("File sent successfully to client {}", client_id);
+    Ok(Status::new(
+        STATUS_OK,
+        format!("{} bytes sent, SHA-256 {}", file_size, to_hex(&digest)),
+    ))
+}
+
+fn send_listing(stream: &mut impl Connection, root: &Path, client_id: u32) -> io::Result<Status> {
+    let root = fs::canonicalize(root)?;
+    let mut entries = Vec::new();
+    list_dir(&root, &root, "", &mut entries)?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let count = entries.len();
+    if count > MAX_ENTRIES as usize {
+        println!("Too many files to list for client {}", client_id);
+        return Ok(Status::new(STATUS_ERROR, format!("{} files, too many to list", count)));
+    }
+    Status::new(STATUS_OK, format!("{} files", count)).write_to(stream)?;
+    Listing { entries }.write_to(stream)?;
+
+    println!("Listed {} files for client {}", count, client_id);
+    Ok(Status::new(STATUS_OK, format!("{} files listed", count)))
+}
+
+/// Adds the files under `dir` named relative to the upload directory `root`, leaving out partial
+/// uploads and checksums.
+///
+/// Links are listed when they lead to a file `send_file` would send, links to directories are
+/// skipped as they may lead in circles.
+fn list_dir(root: &Path, dir: &Path, prefix: &str, entries: &mut Vec<Entry>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        let name = format!("{}{}", prefix, file_name);
+
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if !RESERVED_DIR_NAMES.contains(&name.as_str()) {
+                list_dir(root, &entry.path(), &format!("{}/", name), entries)?;
             }
-            Ok(_) => unreachable!()
+            continue;
+        }
+        if file_type.is_symlink() && !matches!(resolve(root, &entry.path()), Ok(Some(_))) {
+            continue;
         }
-        // Print speed information every PRINT_INTERVAL seconds
-        if client_data.start_time.elapsed() >= next_print {
-            let elapsed_secs = client_data.start_time.elapsed().as_secs() as f64;
-            let average_speed = client_data.total_bytes_received as f64 / elapsed_secs;
-            let instant_speed = (client_data.total_bytes_received - prev_bytes_received) as f64 / PRINT_INTERVAL.as_secs() as f64;
-            let percents = client_data.total_bytes_received as f64 /file_size as f64;
-            println!(
-                "Client {}: Average speed: {} bytes/s & instant speed: {} bytes/s & {:.2}%",
-                client_id, average_speed, instant_speed, percents * 100.0);
 
-            prev_bytes_received = client_data.total_bytes_received;
-            next_print += PRINT_INTERVAL;
+        // Unlike `entry.metadata()`, this follows the links that stay inside.
+        let metadata = fs::metadata(entry.path())?;
+        if !metadata.is_file() {
+            continue;
         }
+
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        entries.push(Entry {
+            name,
+            size: metadata.len(),
+            modified,
+        });
     }
-    println!("{} bytes from {} recieved", client_data.total_bytes_received, file_size);
-    println!("File received successfully for client {}", client_id);
+
+    Ok(())
+}
+
+fn reply(stream: &mut impl Write, status: Status) -> io::Result<Status> {
+    status.write_to(stream)?;
+    Ok(status)
+}
+
+/// Where an upload goes, relative to the upload directory.
+fn upload_path(name: &str) -> PathBuf {
+    let path = sanitize_path(name);
+
+    // The partial uploads and checksums are no place for clients to write into or read from.
+    for reserved in RESERVED_DIR_NAMES {
+        match path.strip_prefix(reserved) {
+            Ok(rest) if !rest.as_os_str().is_empty() => return Path::new(&format!("_{}", reserved)).join(rest),
+            _ => {}
+        }
+    }
+    path
+}
+
+/// `path` with every link resolved, `None` if it leads out of the upload directory `root` or into
+/// its reserved directories. `root` must be canonical.
+fn resolve(root: &Path, path: &Path) -> io::Result<Option<PathBuf>> {
+    let resolved = fs::canonicalize(path)?;
+    let inside = resolved.strip_prefix(root).is_ok_and(|relative| {
+        !RESERVED_DIR_NAMES.iter().any(|reserved| relative.starts_with(reserved))
+    });
+
+    Ok(inside.then_some(resolved))
+}
+
+/// Opens the partial file of an upload at its end, with its length and the hash of what it holds.
+fn open_partial(path: &Path, file_size: u64) -> io::Result<(File, u64, Sha256)> {
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+
+    let mut offset = file.metadata()?.len();
+    if offset > file_size {
+        file.set_len(0)?;
+        offset = 0;
+    }
+    // What was received before counts towards the digest as much as what's still to come.
+    let mut hasher = Sha256::new();
+    io::copy(&mut (&file).take(offset), &mut hasher)?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    Ok((file, offset, hasher))
+}
+
+/// Only complete files ever appear under their name, replacing the empty one reserved here, and
+/// only with their checksum saved.
+///
+/// On failure the partial file stays for the client to resume.
+fn finish(file: File, partial_path: &Path, root: &Path, dir: &Path, name: &str, digest: &[u8]) -> io::Result<PathBuf> {
+    file.sync_all()?;
+    drop(file);
+
+    let (reserved, path) = create_unique(dir, name)?;
+    drop(reserved);
+    if let Err(err) = write_checksum(root, &path, digest).and_then(|()| fs::rename(partial_path, &path)) {
+        let _ = fs::remove_file(&path);
+        return Err(err);
+    }
+
+    Ok(path)
+}
+
+/// Where the checksum of the file at `path` in the upload directory `root` goes.
+fn checksum_path(root: &Path, path: &Path) -> PathBuf {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let name = relative.file_name().unwrap_or_default().to_string_lossy();
+
+    root.join(CHECKSUM_DIR_NAME)
+        .join(relative.with_file_name(format!("{}{}", name, CHECKSUM_SUFFIX)))
+}
+
+/// Saves the digest as `name.sha256` in the checksum directory, in the format `sha256sum -c`
+/// checks. One left behind by an earlier file of the same name is replaced.
+fn write_checksum(root: &Path, path: &Path, digest: &[u8]) -> io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let checksum_path = checksum_path(root, path);
+    if let Some(parent) = checksum_path.parent() {
+        create_dir_all(parent)?;
+    }
+
+    let mut sidecar = File::create(checksum_path)?;
+    writeln!(sidecar, "{}  {}", to_hex(digest), name)?;
+    sidecar.sync_all()
+}
+
+/// The digest `write_checksum` saved for the file, if it's there and readable.
+fn read_checksum(root: &Path, path: &Path) -> Option<Vec<u8>> {
+    let sidecar = fs::read_to_string(checksum_path(root, path)).ok()?;
+    from_hex(sidecar.split_whitespace().next()?).filter(|digest| digest.len() == DIGEST_LEN)
 }
 
 fn main() {
@@ -95,6 +524,17 @@ fn main() {
     let port: u16 = args[2].parse().expect("Invalid port number");
 
     let listener = TcpListener::bind(format!("{}:{}", server_addr, port)).expect("Failed to bind");
+    let partial_dir = Path::new(OUTPUT_DIR).join(PARTIAL_DIR_NAME);
+    create_dir_all(&partial_dir).expect("Failed to create the output directory");
+
+    thread::spawn(move || loop {
+        match expire(&partial_dir, PARTIAL_TTL) {
+            Ok(0) => {}
+            Ok(expired) => println!("Removed {} expired partial uploads", expired),
+            Err(err) => eprintln!("Error removing expired partial uploads: {}", err),
+        }
+        thread::sleep(EXPIRE_INTERVAL);
+    });
 
     let mut client_counter = 0;
 
@@ -104,9 +544,9 @@ fn main() {
                 client_counter += 1;
                 println!("Accepted client connection: {}", client_counter);
 
-                let cloned_stream = stream.try_clone().expect("Failed to clone stream");
+                let mut cloned_stream = stream.try_clone().expect("Failed to clone stream");
                 thread::spawn(move || {
-                    handle_client(cloned_stream, client_counter);
+                    handle_client(&mut cloned_stream, Path::new(OUTPUT_DIR), client_counter);
                 });
             }
             Err(err) => {
@@ -115,3 +555,340 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+#[path = "test_util.rs"]
+mod test_util;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use std::io::Cursor;
+
+    /// The client's end of a session: what it sends, and what the server answers.
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Pipe {
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// An upload directory as `main` prepares it.
+    fn root(name: &str) -> PathBuf {
+        let root = temp_dir(name);
+        create_dir_all(root.join(PARTIAL_DIR_NAME)).unwrap();
+        root
+    }
+
+    /// Runs a session on `input` and returns the server's answers.
+    fn session(root: &Path, input: Vec<u8>) -> Cursor<Vec<u8>> {
+        let mut pipe = Pipe { input: Cursor::new(input), output: Vec::new() };
+        handle_client(&mut pipe, root, 1);
+        Cursor::new(pipe.output)
+    }
+
+    fn request(request: u8) -> Vec<u8> {
+        let mut input = Vec::new();
+        write_hello(&mut input).unwrap();
+        write_request(&mut input, request).unwrap();
+        input
+    }
+
+    /// A PUT of one file announced as `size` bytes, followed by `data` and `digest`.
+    fn put(name: &str, size: u64, data: &[u8], digest: &[u8]) -> Vec<u8> {
+        let mut input = request(REQUEST_PUT);
+        let header = Header { name: name.to_owned(), size, fingerprint: 0 };
+        Manifest { files: vec![header] }.write_to(&mut input).unwrap();
+        input.extend_from_slice(data);
+        input.extend_from_slice(digest);
+        input
+    }
+
+    fn status(output: &mut Cursor<Vec<u8>>) -> u8 {
+        Status::read_from(output).unwrap().code
+    }
+
+    /// Reads the answers up to the go-ahead for the file and returns the offset it resumes from.
+    fn file_ready(output: &mut Cursor<Vec<u8>>) -> u64 {
+        for _ in 0..3 {
+            assert_eq!(status(output), STATUS_OK);
+        }
+        read_u64(output).unwrap()
+    }
+
+    fn finished(output: &Cursor<Vec<u8>>) -> bool {
+        output.position() == output.get_ref().len() as u64
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn receives_exactly_the_announced_size() {
+        let root = root("exact");
+        let data = b"0123456789";
+
+        let mut output = session(&root, put("a.txt", 10, data, &Sha256::digest(data)));
+        assert_eq!(file_ready(&mut output), 0);
+        assert_eq!(status(&mut output), STATUS_OK);
+        assert_eq!(status(&mut output), STATUS_OK);
+        assert!(finished(&output));
+
+        assert_eq!(fs::read(root.join("a.txt")).unwrap(), data);
+        assert!(files(&root.join(PARTIAL_DIR_NAME)).is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn short_stream_is_an_error_and_saves_nothing() {
+        let root = root("short");
+
+        let mut output = session(&root, put("b.txt", 10, b"0123", &[]));
+        assert_eq!(file_ready(&mut output), 0);
+        assert_eq!(status(&mut output), STATUS_ERROR);
+        assert!(finished(&output));
+
+        // Only the partial file is left, for the client to resume.
+        assert_eq!(files(&root), [PARTIAL_DIR_NAME]);
+        let partial = root.join(PARTIAL_DIR_NAME).join(files(&root.join(PARTIAL_DIR_NAME)).remove(0));
+        assert_eq!(fs::read(partial).unwrap(), b"0123");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn over_long_stream_is_an_error_and_leaves_no_file() {
+        let root = root("over-long");
+        let data = b"0123456789abcd";
+
+        let mut output = session(&root, put("c.txt", 10, data, &Sha256::digest(data)));
+        assert_eq!(file_ready(&mut output), 0);
+        // What follows the announced size is taken for the checksum, which can't match.
+        assert_eq!(status(&mut output), STATUS_CHECKSUM_MISMATCH);
+        assert_eq!(status(&mut output), STATUS_SIZE_MISMATCH);
+        assert!(finished(&output));
+
+        assert_eq!(files(&root), [PARTIAL_DIR_NAME]);
+        assert!(files(&root.join(PARTIAL_DIR_NAME)).is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn remaps_reserved_directories() {
+        assert_eq!(upload_path(".partial/x.part"), Path::new("_.partial/x.part"));
+        assert_eq!(upload_path(".checksums/a/b.sha256"), Path::new("_.checksums/a/b.sha256"));
+        assert_eq!(upload_path("../.checksums/a"), Path::new("_.checksums/a"));
+        assert_eq!(upload_path("a/.partial/b"), Path::new("a/.partial/b"));
+        // A file can't take a directory's name, it gets a free one instead.
+        assert_eq!(upload_path(".partial"), Path::new(".partial"));
+    }
+
+    #[test]
+    fn uploads_never_touch_reserved_directories() {
+        let root = root("reserved");
+        let data = b"original";
+        session(&root, put("a.txt", 8, data, &Sha256::digest(data)));
+        let sidecar = fs::read(root.join(CHECKSUM_DIR_NAME).join("a.txt.sha256")).unwrap();
+
+        let forged = b"0000000000000000000000000000000000000000000000000000000000000000  a.txt\n";
+        for name in [".checksums/a.txt.sha256", ".partial/x.part", ".checksums", ".partial"] {
+            let mut output = session(&root, put(name, forged.len() as u64, forged, &Sha256::digest(forged)));
+            assert_eq!(file_ready(&mut output), 0, "{}", name);
+            assert_eq!(status(&mut output), STATUS_OK, "{}", name);
+        }
+
+        assert_eq!(fs::read(root.join(CHECKSUM_DIR_NAME).join("a.txt.sha256")).unwrap(), sidecar);
+        assert_eq!(
+            files(&root.join(CHECKSUM_DIR_NAME)),
+            [".checksums (1).sha256", ".partial (1).sha256", "_.checksums", "_.partial", "a.txt.sha256"]
+        );
+        assert!(files(&root.join(PARTIAL_DIR_NAME)).is_empty());
+        assert_eq!(
+            files(&root),
+            [".checksums", ".checksums (1)", ".partial", ".partial (1)", "_.checksums", "_.partial", "a.txt"]
+        );
+        assert_eq!(fs::read(root.join("_.checksums/a.txt.sha256")).unwrap(), forged);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn checksum_sidecar_round_trips() {
+        let root = temp_dir("sidecar");
+        let path = root.join("dir").join("a b.txt");
+        let digest = Sha256::digest(b"data");
+
+        assert_eq!(read_checksum(&root, &path), None);
+        write_checksum(&root, &path, &digest).unwrap();
+
+        let sidecar = root.join(CHECKSUM_DIR_NAME).join("dir").join("a b.txt.sha256");
+        assert_eq!(fs::read_to_string(&sidecar).unwrap(), format!("{}  a b.txt\n", to_hex(&digest)));
+        assert_eq!(read_checksum(&root, &path), Some(digest.to_vec()));
+
+        // A later file of the same name replaces it, a damaged one is ignored.
+        write_checksum(&root, &path, &[0xab; DIGEST_LEN]).unwrap();
+        assert_eq!(read_checksum(&root, &path), Some(vec![0xab; DIGEST_LEN]));
+        fs::write(&sidecar, "abcd  a b.txt\n").unwrap();
+        assert_eq!(read_checksum(&root, &path), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn rejects_mismatched_checksums() {
+        let root = root("mismatch");
+
+        let mut output = session(&root, put("d.txt", 4, b"data", &Sha256::digest(b"date")));
+        assert_eq!(file_ready(&mut output), 0);
+        assert_eq!(status(&mut output), STATUS_CHECKSUM_MISMATCH);
+        assert_eq!(status(&mut output), STATUS_ERROR);
+        assert!(finished(&output));
+
+        assert_eq!(files(&root), [PARTIAL_DIR_NAME]);
+        assert!(files(&root.join(PARTIAL_DIR_NAME)).is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn downloads_report_files_rotting_on_disk() {
+        let root = root("rotting");
+        session(&root, put("e.txt", 4, b"data", &Sha256::digest(b"data")));
+        fs::write(root.join("e.txt"), b"date").unwrap();
+
+        let mut input = request(REQUEST_GET);
+        write_name(&mut input, "e.txt").unwrap();
+        let mut output = session(&root, input);
+        assert_eq!(status(&mut output), STATUS_OK);
+        assert_eq!(status(&mut output), STATUS_OK);
+        assert_eq!(read_u64(&mut output).unwrap(), 4);
+
+        let mut data = [0; 4];
+        output.read_exact(&mut data).unwrap();
+        let mut digest = [0; DIGEST_LEN];
+        output.read_exact(&mut digest).unwrap();
+        assert_eq!(&data, b"date");
+        assert_eq!(digest[..], Sha256::digest(b"data")[..]);
+        assert_eq!(status(&mut output), STATUS_CHECKSUM_MISMATCH);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// An upload directory inside `name` holding `a.txt`, next to a file and a directory it must
+    /// not give away, with links to all of them.
+    #[cfg(unix)]
+    fn sandbox(name: &str) -> (PathBuf, PathBuf) {
+        use std::os::unix::fs::symlink;
+
+        let parent = temp_dir(name);
+        let root = parent.join("root");
+        create_dir_all(root.join(PARTIAL_DIR_NAME)).unwrap();
+        create_dir_all(root.join(CHECKSUM_DIR_NAME)).unwrap();
+        create_dir_all(parent.join("secrets")).unwrap();
+        fs::write(parent.join("secret.txt"), b"secret").unwrap();
+        fs::write(parent.join("secrets/key"), b"secret").unwrap();
+        fs::write(root.join(PARTIAL_DIR_NAME).join("x.part"), b"partial").unwrap();
+        fs::write(root.join("a.txt"), b"inside").unwrap();
+
+        symlink(parent.join("secret.txt"), root.join("absolute-link")).unwrap();
+        symlink("../secret.txt", root.join("relative-link")).unwrap();
+        symlink("../secrets", root.join("dir-link")).unwrap();
+        symlink(".partial/x.part", root.join("partial-link")).unwrap();
+        symlink("a.txt", root.join("inside-link")).unwrap();
+        (parent, root)
+    }
+
+    #[cfg(unix)]
+    fn get(root: &Path, name: &str) -> (u8, Vec<u8>) {
+        let mut input = request(REQUEST_GET);
+        write_name(&mut input, name).unwrap();
+        let mut output = session(root, input);
+        assert_eq!(status(&mut output), STATUS_OK);
+
+        let code = status(&mut output);
+        let mut data = Vec::new();
+        if code == STATUS_OK {
+            data = vec![0; read_u64(&mut output).unwrap() as usize];
+            output.read_exact(&mut data).unwrap();
+        }
+        (code, data)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn get_stays_in_the_upload_directory() {
+        let (parent, root) = sandbox("get-sandbox");
+        let absolute = parent.join("secret.txt").to_string_lossy().into_owned();
+
+        for name in [
+            "../secret.txt",
+            "../../secret.txt",
+            "..\\secret.txt",
+            absolute.as_str(),
+            "absolute-link",
+            "relative-link",
+            "dir-link/key",
+            "partial-link",
+            ".partial/x.part",
+            "../root/.partial/x.part",
+            "dir-link",
+        ] {
+            assert_eq!(get(&root, name).0, STATUS_NOT_FOUND, "{:?}", name);
+        }
+
+        // Whatever the path, only a file of the upload directory is sent.
+        assert_eq!(get(&root, "../a.txt"), (STATUS_OK, b"inside".to_vec()));
+        assert_eq!(get(&root, "/a.txt"), (STATUS_OK, b"inside".to_vec()));
+        assert_eq!(get(&root, "inside-link"), (STATUS_OK, b"inside".to_vec()));
+
+        fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn list_stays_in_the_upload_directory() {
+        let (parent, root) = sandbox("list-sandbox");
+        create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub/b.txt"), b"b").unwrap();
+        std::os::unix::fs::symlink("..", root.join("sub/loop")).unwrap();
+
+        let mut output = session(&root, request(REQUEST_LIST));
+        assert_eq!(status(&mut output), STATUS_OK);
+        assert_eq!(status(&mut output), STATUS_OK);
+        let names: Vec<_> = Listing::read_from(&mut output).unwrap().entries.into_iter().map(|entry| entry.name).collect();
+        assert_eq!(status(&mut output), STATUS_OK);
+
+        assert_eq!(names, ["a.txt", "inside-link", "sub/b.txt"]);
+
+        fs::remove_dir_all(&parent).unwrap();
+    }
+}
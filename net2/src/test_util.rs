@@ -0,0 +1,13 @@
+//! Helpers shared by the tests of the library and the server.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+/// An empty directory for one test, unique to the test process.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("net2-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
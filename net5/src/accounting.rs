@@ -0,0 +1,467 @@
+//! Per-user traffic totals in daily CSV files, `koblas-YYYY-MM-DD.csv`, one row per session.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fmt, iter, thread};
+use tracing::error;
+
+const PREFIX: &str = "koblas-";
+const EXTENSION: &str = ".csv";
+const HEADER: &str = "time,user,destination,sent,received";
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Calendar day in UTC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    year: i64,
+    month: u32,
+    day: u32,
+}
+
+impl Date {
+    pub fn today() -> Self {
+        Self::from_secs(now())
+    }
+
+    fn from_secs(secs: u64) -> Self {
+        Self::from_days((secs / SECS_PER_DAY) as i64)
+    }
+
+    /// Days since 1970-01-01, see <https://howardhinnant.github.io/date_algorithms.html>.
+    fn from_days(days: i64) -> Self {
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        Self { year, month, day }
+    }
+
+    fn days(&self) -> i64 {
+        let year = self.year - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let month = i64::from(self.month);
+        let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5
+            + i64::from(self.day)
+            - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+        era * 146_097 + doe - 719_468
+    }
+}
+
+impl FromStr for Date {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid date `{s}`, expected YYYY-MM-DD");
+
+        let mut parts = s.splitn(3, '-');
+        let (Some(year), Some(month), Some(day)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let date = Self {
+            year: year.parse().map_err(|_| invalid())?,
+            month: month.parse().map_err(|_| invalid())?,
+            day: day.parse().map_err(|_| invalid())?,
+        };
+
+        // Out of range days, like February 30th, don't survive the round trip.
+        if !(1..=12).contains(&date.month) || Self::from_days(date.days()) != date {
+            return Err(invalid());
+        }
+
+        Ok(date)
+    }
+}
+
+impl Display for Date {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn timestamp(secs: u64) -> String {
+    let rem = secs % SECS_PER_DAY;
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        Date::from_secs(secs),
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+fn path(dir: &Path, date: Date) -> PathBuf {
+    dir.join(format!("{PREFIX}{date}{EXTENSION}"))
+}
+
+/// Quotes fields that need it; control characters are replaced so that every row is one line.
+fn field(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .map(|c| {
+            if c.is_control() {
+                char::REPLACEMENT_CHARACTER
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    if value.contains([',', '"']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn parse_row(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(String::new()),
+            (c, _) => fields.last_mut().unwrap().push(c),
+        }
+    }
+
+    fields
+}
+
+struct Record {
+    time: u64,
+    user: Option<String>,
+    destination: String,
+    sent: u64,
+    received: u64,
+}
+
+/// Appends session totals from a dedicated thread, starting a new file at midnight UTC.
+pub struct Accounting {
+    records: mpsc::Sender<Record>,
+}
+
+impl Accounting {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let dir = dir.to_owned();
+        let (records, rx) = mpsc::channel::<Record>();
+        thread::Builder::new()
+            .name("accounting".into())
+            .spawn(move || {
+                let mut current = None;
+                let res = (|| {
+                    while let Ok(record) = rx.recv() {
+                        for record in iter::once(record).chain(rx.try_iter()) {
+                            write(&dir, &mut current, &record)?;
+                        }
+                        if let Some((_, file)) = &mut current {
+                            file.flush()?;
+                        }
+                    }
+                    io::Result::Ok(())
+                })();
+
+                if let Err(err) = res {
+                    error!("accounting stopped: {err}");
+                }
+            })?;
+
+        Ok(Self { records })
+    }
+
+    pub fn record(&self, user: Option<&str>, destination: &str, sent: u64, received: u64) {
+        let record = Record {
+            time: now(),
+            user: user.map(str::to_owned),
+            destination: destination.to_owned(),
+            sent,
+            received,
+        };
+
+        // The writer is gone only if it failed, which it has reported already.
+        let _ = self.records.send(record);
+    }
+}
+
+fn write(
+    dir: &Path,
+    current: &mut Option<(Date, BufWriter<File>)>,
+    record: &Record,
+) -> io::Result<()> {
+    let date = Date::from_secs(record.time);
+
+    let file = match current {
+        Some((current, file)) if *current == date => file,
+        _ => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path(dir, date))?;
+            let mut file = BufWriter::new(file);
+            if file.get_ref().metadata()?.len() == 0 {
+                writeln!(file, "{HEADER}")?;
+            }
+
+            &mut current.insert((date, file)).1
+        }
+    };
+
+    writeln!(
+        file,
+        "{},{},{},{},{}",
+        timestamp(record.time),
+        field(record.user.as_deref().unwrap_or_default()),
+        field(&record.destination),
+        record.sent,
+        record.received
+    )
+}
+
+#[derive(Default)]
+struct Totals {
+    sessions: u64,
+    sent: u64,
+    received: u64,
+}
+
+impl Totals {
+    fn add(&mut self, sent: u64, received: u64) {
+        self.sessions += 1;
+        self.sent += sent;
+        self.received += received;
+    }
+}
+
+/// Prints daily totals, top users and top destinations for the files in `[from, to]`.
+pub fn report(dir: &Path, from: Option<Date>, to: Option<Date>, top: usize) -> io::Result<()> {
+    let to = to.unwrap_or_else(Date::today);
+
+    let mut days = BTreeMap::<Date, Totals>::new();
+    let mut users = HashMap::<String, Totals>::new();
+    let mut destinations = HashMap::<String, Totals>::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(date) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(PREFIX)?.strip_suffix(EXTENSION))
+            .and_then(|date| date.parse::<Date>().ok())
+        else {
+            continue;
+        };
+        if from.is_some_and(|from| date < from) || date > to {
+            continue;
+        }
+
+        for line in fs::read_to_string(entry.path())?.lines().skip(1) {
+            let row = parse_row(line);
+            let [_, user, destination, sent, received] = &row[..] else {
+                continue;
+            };
+            let (Ok(sent), Ok(received)) = (sent.parse(), received.parse()) else {
+                continue;
+            };
+
+            let user = if user.is_empty() { "(anonymous)" } else { user };
+            days.entry(date).or_default().add(sent, received);
+            users
+                .entry(user.to_owned())
+                .or_default()
+                .add(sent, received);
+            destinations
+                .entry(destination.clone())
+                .or_default()
+                .add(sent, received);
+        }
+    }
+
+    let mut out = io::stdout().lock();
+
+    writeln!(out, "Daily totals")?;
+    print_table(
+        &mut out,
+        "date",
+        days.iter().map(|(date, t)| (date.to_string(), t)),
+    )?;
+
+    for (title, column, totals) in [
+        ("Top users", "user", users),
+        ("Top destinations", "destination", destinations),
+    ] {
+        let mut rows: Vec<_> = totals.into_iter().collect();
+        rows.sort_by_key(|(name, t)| (Reverse(t.sent + t.received), name.clone()));
+
+        writeln!(out, "\n{title}")?;
+        print_table(
+            &mut out,
+            column,
+            rows.iter().take(top).map(|(name, t)| (name.clone(), t)),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn print_table<'a>(
+    out: &mut impl Write,
+    column: &str,
+    rows: impl Iterator<Item = (String, &'a Totals)>,
+) -> io::Result<()> {
+    let rows: Vec<_> = rows.collect();
+    let width = rows
+        .iter()
+        .map(|(name, _)| name.chars().count())
+        .chain([column.len()])
+        .max()
+        .unwrap_or_default();
+
+    writeln!(
+        out,
+        "{column:<width$}  {:>8}  {:>16}  {:>16}",
+        "sessions", "sent", "received"
+    )?;
+    for (name, totals) in rows {
+        writeln!(
+            out,
+            "{name:<width$}  {:>8}  {:>16}  {:>16}",
+            totals.sessions, totals.sent, totals.received
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> Date {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn dates_round_trip_through_days() {
+        assert_eq!(Date::from_days(0), date("1970-01-01"));
+        assert_eq!(date("1970-01-01").days(), 0);
+        assert_eq!(date("2000-02-29").days(), 11_016);
+        assert_eq!(Date::from_days(-1), date("1969-12-31"));
+
+        for days in (0..800_000).step_by(7) {
+            let date = Date::from_days(days);
+            assert_eq!(date.days(), days);
+            assert_eq!(date.to_string().parse::<Date>(), Ok(date), "{days}");
+        }
+    }
+
+    #[test]
+    fn rejects_impossible_dates() {
+        for s in [
+            "2023-02-29",
+            "2024-02-30",
+            "2024-13-01",
+            "2024-00-10",
+            "2024-04-31",
+            "2024-01-00",
+            "2024-01",
+            "2024/01/01",
+            "today",
+            "",
+        ] {
+            assert!(s.parse::<Date>().is_err(), "{s}");
+        }
+        assert!("2024-02-29".parse::<Date>().is_ok());
+    }
+
+    #[test]
+    fn timestamps_are_utc() {
+        assert_eq!(timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(timestamp(951_782_399), "2000-02-28T23:59:59Z");
+        assert_eq!(timestamp(951_782_400), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn fields_round_trip_through_rows() {
+        let values = ["alice", "", "a,b", "say \"hi\"", "\"", ",,", "héllo"];
+        let line = values
+            .iter()
+            .map(|value| field(value))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        assert_eq!(parse_row(&line), values);
+    }
+
+    #[test]
+    fn fields_stay_on_one_line() {
+        let value = field("evil\n2024-01-01T00:00:00Z,x,y,1,1");
+
+        assert!(!value.contains('\n'));
+        assert_eq!(parse_row(&value).len(), 1);
+    }
+
+    #[test]
+    fn writes_a_header_once_per_file() {
+        let dir = std::env::temp_dir().join(format!("koblas-accounting-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let record = |time, user: Option<&str>| Record {
+            time,
+            user: user.map(str::to_owned),
+            destination: "example.com".to_owned(),
+            sent: 10,
+            received: 20,
+        };
+        let mut current = None;
+        write(&dir, &mut current, &record(0, Some("a,b"))).unwrap();
+        write(&dir, &mut current, &record(SECS_PER_DAY, None)).unwrap();
+        // As after a restart, the file of the day is appended to.
+        drop(current);
+        let mut current = None;
+        write(&dir, &mut current, &record(SECS_PER_DAY + 1, Some("c"))).unwrap();
+        drop(current);
+
+        let first = fs::read_to_string(path(&dir, date("1970-01-01"))).unwrap();
+        let second = fs::read_to_string(path(&dir, date("1970-01-02"))).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            first,
+            format!("{HEADER}\n1970-01-01T00:00:00Z,\"a,b\",example.com,10,20\n")
+        );
+        assert_eq!(second.lines().filter(|line| *line == HEADER).count(), 1);
+        assert_eq!(second.lines().count(), 3);
+    }
+}
@@ -0,0 +1,450 @@
+//! Credential backends beside the static `users` map: htpasswd, an external command and
+//! an HTTP webhook.
+
+use crate::config::{Auth, Config};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use md5::{Digest, Md5};
+use serde::Serialize;
+use sha1::Sha1;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::{fs, task, time};
+
+/// How long the command and the webhook have to come to a verdict.
+const BACKEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+const APR1_MAGIC: &str = "$apr1$";
+const CRYPT_ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+enum Backend<'a> {
+    Htpasswd(&'a Path),
+    Command(&'a [String]),
+    Webhook(&'a str),
+}
+
+impl Backend<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Htpasswd(_) => "htpasswd",
+            Self::Command(_) => "command",
+            Self::Webhook(_) => "webhook",
+        }
+    }
+}
+
+struct Verdict {
+    /// Keyed hash of the password, so that the cache holds no plain text.
+    password: u64,
+    accepted: bool,
+    expires: Instant,
+}
+
+pub struct Authenticator {
+    client: reqwest::Client,
+    cache: Mutex<HashMap<String, Verdict>>,
+    hasher: RandomState,
+}
+
+impl Authenticator {
+    pub fn new() -> io::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(BACKEND_TIMEOUT)
+            .build()
+            .map_err(io::Error::other)?;
+
+        Ok(Self {
+            client,
+            cache: Mutex::new(HashMap::new()),
+            hasher: RandomState::new(),
+        })
+    }
+
+    /// Checks credentials against `users` and then each configured backend until one
+    /// knows the user.
+    ///
+    /// Backend failures are errors rather than rejections and are never cached.
+    pub async fn verify(&self, config: &Config, user: &str, password: &str) -> io::Result<bool> {
+        if config.users.contains_key(user) {
+            return Ok(config.verify(user, password));
+        }
+
+        let auth = &config.auth;
+        let hash = self.hasher.hash_one(password);
+        if let Some(accepted) = self.cached(user, hash) {
+            return Ok(accepted);
+        }
+
+        let backends = auth
+            .htpasswd
+            .as_deref()
+            .map(Backend::Htpasswd)
+            .into_iter()
+            .chain((!auth.command.is_empty()).then_some(Backend::Command(&auth.command)))
+            .chain(auth.webhook.as_deref().map(Backend::Webhook));
+
+        let mut failure = None;
+        for backend in backends {
+            let res = match backend {
+                Backend::Htpasswd(path) => htpasswd(path, user, password).await,
+                Backend::Command(argv) => command(argv, user, password).await,
+                Backend::Webhook(url) => self.webhook(url, user, password).await,
+            };
+
+            match res {
+                Ok(Some(accepted)) => {
+                    self.store(auth, user, hash, accepted);
+                    return Ok(accepted);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    let err = io::Error::new(err.kind(), format!("{}: {err}", backend.name()));
+                    failure.get_or_insert(err);
+                }
+            }
+        }
+
+        match failure {
+            Some(err) => Err(err),
+            None => {
+                self.store(auth, user, hash, false);
+                Ok(false)
+            }
+        }
+    }
+
+    fn cached(&self, user: &str, password: u64) -> Option<bool> {
+        let cache = self.cache.lock().unwrap();
+        let verdict = cache.get(user)?;
+
+        (verdict.password == password && verdict.expires > Instant::now())
+            .then_some(verdict.accepted)
+    }
+
+    fn store(&self, auth: &Auth, user: &str, password: u64, accepted: bool) {
+        if auth.cache_ttl == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, verdict| verdict.expires > now);
+        cache.insert(
+            user.to_owned(),
+            Verdict {
+                password,
+                accepted,
+                expires: now + Duration::from_secs(auth.cache_ttl),
+            },
+        );
+    }
+
+    async fn webhook(&self, url: &str, user: &str, password: &str) -> io::Result<Option<bool>> {
+        #[derive(Serialize)]
+        struct Request<'a> {
+            username: &'a str,
+            password: &'a str,
+        }
+
+        let res = self
+            .client
+            .post(url)
+            .json(&Request {
+                username: user,
+                password,
+            })
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+
+        match res.status() {
+            status if status.is_success() => Ok(Some(true)),
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => Ok(Some(false)),
+            status => Err(io::Error::other(format!("replied {status}"))),
+        }
+    }
+}
+
+/// `None` if the file has no entry for `user`.
+async fn htpasswd(path: &Path, user: &str, password: &str) -> io::Result<Option<bool>> {
+    let file = fs::read_to_string(path).await?;
+    let entry = file
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| *name == user);
+    let Some((_, hash)) = entry else {
+        return Ok(None);
+    };
+
+    // bcrypt and argon2 are slow on purpose, so they stay off the runtime thread.
+    let (hash, password) = (hash.trim().to_owned(), password.to_owned());
+    let accepted = task::spawn_blocking(move || check_hash(&hash, &password)).await??;
+
+    Ok(Some(accepted))
+}
+
+/// Compares secrets in a time that depends only on their length, so that the time taken
+/// doesn't tell a guesser how much of a token was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn check_hash(hash: &str, password: &str) -> io::Result<bool> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+
+    if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).map_err(|_| invalid("malformed bcrypt hash"))
+    } else if let Some(rest) = hash.strip_prefix(APR1_MAGIC) {
+        let salt = rest.split('$').next().unwrap_or_default();
+        Ok(apr1(password.as_bytes(), salt.as_bytes()) == hash)
+    } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+        Ok(STANDARD.encode(Sha1::digest(password)) == digest)
+    } else if hash.starts_with("$argon2") {
+        let hash = PasswordHash::new(hash).map_err(|_| invalid("malformed argon2 hash"))?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok())
+    } else {
+        Err(invalid(
+            "unsupported hash, expected bcrypt, apr1, SHA-1 or argon2",
+        ))
+    }
+}
+
+/// Apache's variant of MD5-crypt, the default of `htpasswd` without `-B`.
+fn apr1(password: &[u8], salt: &[u8]) -> String {
+    let salt = &salt[..salt.len().min(8)];
+
+    let alternate = Md5::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+
+    let mut md5 = Md5::new()
+        .chain_update(password)
+        .chain_update(APR1_MAGIC)
+        .chain_update(salt);
+    for chunk in (0..password.len()).step_by(16) {
+        md5.update(&alternate[..(password.len() - chunk).min(16)]);
+    }
+    let mut len = password.len();
+    while len > 0 {
+        if len & 1 == 1 {
+            md5.update([0]);
+        } else {
+            md5.update(&password[..1]);
+        }
+        len >>= 1;
+    }
+    let mut digest = md5.finalize();
+
+    for round in 0..1000 {
+        let mut md5 = Md5::new();
+        if round & 1 == 1 {
+            md5.update(password);
+        } else {
+            md5.update(digest);
+        }
+        if round % 3 != 0 {
+            md5.update(salt);
+        }
+        if round % 7 != 0 {
+            md5.update(password);
+        }
+        if round & 1 == 1 {
+            md5.update(digest);
+        } else {
+            md5.update(password);
+        }
+        digest = md5.finalize();
+    }
+
+    let mut out = format!("{APR1_MAGIC}{}$", String::from_utf8_lossy(salt));
+    let mut push = |value: u32, chars: usize| {
+        let mut value = value;
+        for _ in 0..chars {
+            out.push(CRYPT_ALPHABET[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    for [a, b, c] in [[0, 6, 12], [1, 7, 13], [2, 8, 14], [3, 9, 15], [4, 10, 5]] {
+        let value = u32::from(digest[a]) << 16 | u32::from(digest[b]) << 8 | u32::from(digest[c]);
+        push(value, 4);
+    }
+    push(u32::from(digest[11]), 2);
+
+    out
+}
+
+/// Exit status 0 accepts and 1 rejects, anything else is a failure.
+async fn command(argv: &[String], user: &str, password: &str) -> io::Result<Option<bool>> {
+    // The credentials are passed as lines.
+    if user.contains('\n') || password.contains('\n') {
+        return Ok(Some(false));
+    }
+
+    let mut child = Command::new(&argv[0])
+        .args(&argv[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = format!("{user}\n{password}\n");
+
+    let status = time::timeout(BACKEND_TIMEOUT, async {
+        match stdin.write_all(input.as_bytes()).await {
+            // The program may decide without reading everything.
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err),
+            _ => drop(stdin),
+        }
+        child.wait().await
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out"))??;
+
+    match status.code() {
+        Some(0) => Ok(Some(true)),
+        Some(1) => Ok(Some(false)),
+        _ => Err(io::Error::other(format!("exited with {status}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn config(toml: &str) -> Config {
+        toml.parse().unwrap()
+    }
+
+    fn command_config(status: u8) -> Config {
+        config(&format!(
+            "[auth]\ncommand = [\"sh\", \"-c\", \"exit {status}\"]"
+        ))
+    }
+
+    #[test]
+    fn checks_published_hashes() {
+        let vectors = [
+            // Apache's htpasswd documentation.
+            ("$apr1$qHDFfhPC$nITSVHgYbDAK1Y0acGRnY0", "myPassword"),
+            ("{SHA}VBPuJHI7uixaa6LQGWx4s+5GKNE=", "myPassword"),
+            // Openwall's crypt_blowfish test vectors.
+            (
+                "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+                "U*U",
+            ),
+            (
+                "$2a$05$CCCCCCCCCCCCCCCCCCCCC.VGOzA784oUp/Z0DY336zx7pLYAy0lwK",
+                "U*U*",
+            ),
+            // The argon2 reference implementation's README.
+            (
+                "$argon2i$v=19$m=65536,t=2,p=4$c29tZXNhbHQ$RdescudvJCsgt3ub+b+dWRWJTmaaJObG",
+                "password",
+            ),
+        ];
+
+        for (hash, password) in vectors {
+            assert!(check_hash(hash, password).unwrap(), "{hash}");
+            assert!(!check_hash(hash, "wrong").unwrap(), "{hash}");
+        }
+    }
+
+    #[test]
+    fn apr1_handles_long_passwords() {
+        let password = b"correct horse battery staple";
+
+        assert_eq!(
+            apr1(password, b"1234abcd"),
+            "$apr1$1234abcd$UNdk5LuAF914/XUHot3pC/"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_and_malformed_hashes() {
+        for hash in ["plain", "$1$salt$hash", "$2a$05$short"] {
+            let err = check_hash(hash, "password").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{hash}");
+        }
+    }
+
+    #[test]
+    fn compares_in_full() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[tokio::test]
+    async fn failed_command_is_neither_rejection_nor_cached() {
+        let auth = Authenticator::new().unwrap();
+
+        let err = auth
+            .verify(&command_config(2), "alice", "secret")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("command: "), "{err}");
+        assert!(auth.cache.lock().unwrap().is_empty());
+
+        let accepted = auth.verify(&command_config(0), "alice", "secret").await;
+        assert!(accepted.unwrap());
+    }
+
+    #[tokio::test]
+    async fn failed_webhook_is_neither_rejection_nor_cached() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf).await;
+            let reply = "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+            stream.write_all(reply.as_bytes()).await.unwrap();
+        });
+
+        let auth = Authenticator::new().unwrap();
+        let config = config(&format!("[auth]\nwebhook = \"{url}\""));
+
+        let err = auth.verify(&config, "alice", "secret").await.unwrap_err();
+        assert!(err.to_string().contains("503"), "{err}");
+        assert!(auth.cache.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn verdicts_expire_after_the_ttl() {
+        let auth = Authenticator::new().unwrap();
+        let accept = command_config(0);
+        let reject = command_config(1);
+
+        let before = Instant::now();
+        assert!(auth.verify(&accept, "alice", "secret").await.unwrap());
+        {
+            let cache = auth.cache.lock().unwrap();
+            let expires = cache["alice"].expires;
+            assert!(expires >= before + Duration::from_secs(accept.auth.cache_ttl));
+            assert!(expires <= Instant::now() + Duration::from_secs(accept.auth.cache_ttl));
+        }
+
+        // While cached the rejecting command isn't consulted.
+        assert!(auth.verify(&reject, "alice", "secret").await.unwrap());
+
+        auth.cache.lock().unwrap().get_mut("alice").unwrap().expires = Instant::now();
+        assert!(!auth.verify(&reject, "alice", "secret").await.unwrap());
+    }
+}
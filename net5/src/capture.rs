@@ -0,0 +1,444 @@
+//! pcapng capture of relayed sessions, see <https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-02.html>.
+//!
+//! The proxy only sees payload, so every chunk is wrapped in synthesized IP and TCP
+//! headers, once on the client leg and once on the peer leg, with sequence numbers
+//! that let Wireshark follow both streams.
+
+use crate::config::Capture;
+use crate::proxy_protocol::same_family;
+use crate::relay::{Direction, Tap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+const SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION: u32 = 0x1;
+const ENHANCED_PACKET: u32 = 0x6;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+/// Raw IP, the version nibble tells IPv4 and IPv6 apart.
+const LINKTYPE_RAW: u16 = 101;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+impl Capture {
+    pub fn matches(
+        &self,
+        user: Option<&str>,
+        client: SocketAddr,
+        domain: Option<&str>,
+        peer: SocketAddr,
+    ) -> bool {
+        (self.users.is_empty() || user.is_some_and(|user| self.users.iter().any(|u| u == user)))
+            && (self.clients.is_empty()
+                || self.clients.iter().any(|cidr| cidr.contains(client.ip())))
+            && (self.dest.is_empty()
+                || self
+                    .dest
+                    .iter()
+                    .any(|dest| dest.matches(domain, Some(peer.ip()))))
+    }
+}
+
+/// Capture file shared by all sessions, blocks are written on a dedicated thread.
+pub struct Recorder {
+    blocks: mpsc::Sender<Vec<u8>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        // Section length is unknown up front.
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        file.write_all(&block(SECTION_HEADER, &shb))?;
+
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        file.write_all(&block(INTERFACE_DESCRIPTION, &idb))?;
+        file.flush()?;
+
+        let (blocks, rx) = mpsc::channel::<Vec<u8>>();
+        thread::Builder::new()
+            .name("capture".into())
+            .spawn(move || {
+                let res = (|| {
+                    while let Ok(block) = rx.recv() {
+                        file.write_all(&block)?;
+                        for block in rx.try_iter() {
+                            file.write_all(&block)?;
+                        }
+                        file.flush()?;
+                    }
+                    io::Result::Ok(())
+                })();
+
+                if let Err(err) = res {
+                    error!("capture stopped: {err}");
+                }
+            })?;
+
+        Ok(Self { blocks })
+    }
+
+    /// Starts recording a session and writes the handshakes of both legs.
+    ///
+    /// `proxy` is the address the client connected to and `egress` the source of the
+    /// outbound connection.
+    pub fn session(
+        &self,
+        client: SocketAddr,
+        proxy: SocketAddr,
+        egress: SocketAddr,
+        peer: SocketAddr,
+    ) -> Session {
+        let mut legs = [Leg::new(client, proxy), Leg::new(egress, peer)];
+
+        let mut packets = Vec::new();
+        for leg in &mut legs {
+            packets.push(leg.packet(0, TCP_SYN, &[]));
+            packets.push(leg.packet(1, TCP_SYN | TCP_ACK, &[]));
+            packets.push(leg.packet(0, TCP_ACK, &[]));
+        }
+
+        let session = Session {
+            blocks: self.blocks.clone(),
+            legs: Mutex::new(legs),
+        };
+        session.write(packets);
+        session
+    }
+}
+
+/// One recorded session, legs that weren't closed by both sides are reset on drop.
+pub struct Session {
+    blocks: mpsc::Sender<Vec<u8>>,
+    legs: Mutex<[Leg; 2]>,
+}
+
+impl Session {
+    fn write(&self, packets: Vec<Vec<u8>>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        for packet in packets {
+            // The writer is gone only if it failed, which it has reported already.
+            let _ = self.blocks.send(enhanced_packet(timestamp, &packet));
+        }
+    }
+
+    /// Emits the same chunk or flags on the leg it arrived on and then on the one it leaves by.
+    fn emit(&self, dir: Direction, flags: u8, data: &[u8]) {
+        let (side, order) = match dir {
+            Direction::Upload => (0, [0, 1]),
+            Direction::Download => (1, [1, 0]),
+        };
+
+        let packets = {
+            let mut legs = self.legs.lock().unwrap();
+            order.map(|i| legs[i].packet(side, flags, data))
+        };
+        self.write(packets.into());
+    }
+}
+
+impl Tap for Session {
+    fn data(&self, dir: Direction, data: &[u8]) {
+        self.emit(dir, TCP_PSH | TCP_ACK, data);
+    }
+
+    fn fin(&self, dir: Direction) {
+        self.emit(dir, TCP_FIN | TCP_ACK, &[]);
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let packets: Vec<_> = {
+            let legs = self.legs.get_mut().unwrap();
+            legs.iter_mut()
+                .filter(|leg| !leg.fin.iter().all(|fin| *fin))
+                .map(|leg| leg.packet(0, TCP_RST | TCP_ACK, &[]))
+                .collect()
+        };
+        self.write(packets);
+    }
+}
+
+/// Synthesized connection, side 0 is the one that opened it.
+struct Leg {
+    ends: [SocketAddr; 2],
+    seq: [u32; 2],
+    fin: [bool; 2],
+    id: u16,
+}
+
+impl Leg {
+    fn new(from: SocketAddr, to: SocketAddr) -> Self {
+        let (from, to) = same_family(from, to);
+
+        Self {
+            ends: [from, to],
+            seq: [0, 0],
+            fin: [false, false],
+            id: 0,
+        }
+    }
+
+    /// Builds an IP packet sent by `side` and advances its sequence number.
+    fn packet(&mut self, side: usize, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let (src, dst) = (self.ends[side], self.ends[1 - side]);
+        let seq = self.seq[side];
+        let ack = if flags & TCP_ACK != 0 {
+            self.seq[1 - side]
+        } else {
+            0
+        };
+
+        let mut len = payload.len() as u32;
+        if flags & (TCP_SYN | TCP_FIN) != 0 {
+            len += 1;
+        }
+        self.seq[side] = seq.wrapping_add(len);
+        if flags & TCP_FIN != 0 {
+            self.fin[side] = true;
+        }
+        self.id = self.id.wrapping_add(1);
+
+        let mut tcp = Vec::with_capacity(20 + payload.len());
+        tcp.extend_from_slice(&src.port().to_be_bytes());
+        tcp.extend_from_slice(&dst.port().to_be_bytes());
+        tcp.extend_from_slice(&seq.to_be_bytes());
+        tcp.extend_from_slice(&ack.to_be_bytes());
+        tcp.extend_from_slice(&[5 << 4, flags]);
+        tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+        tcp.extend_from_slice(&[0, 0, 0, 0]);
+        tcp.extend_from_slice(payload);
+
+        let mut pseudo = Vec::with_capacity(40);
+        let mut ip = Vec::with_capacity(40 + tcp.len());
+        match (src.ip(), dst.ip()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                pseudo.extend_from_slice(&src.octets());
+                pseudo.extend_from_slice(&dst.octets());
+                pseudo.extend_from_slice(&[0, 6]);
+                pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+
+                ip.extend_from_slice(&[0x45, 0]);
+                ip.extend_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+                ip.extend_from_slice(&self.id.to_be_bytes());
+                // Don't fragment, TTL 64, TCP.
+                ip.extend_from_slice(&[0x40, 0, 64, 6, 0, 0]);
+                ip.extend_from_slice(&src.octets());
+                ip.extend_from_slice(&dst.octets());
+
+                let sum = checksum(&[&ip]);
+                ip[10..12].copy_from_slice(&sum.to_be_bytes());
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                pseudo.extend_from_slice(&src.octets());
+                pseudo.extend_from_slice(&dst.octets());
+                pseudo.extend_from_slice(&(tcp.len() as u32).to_be_bytes());
+                pseudo.extend_from_slice(&[0, 0, 0, 6]);
+
+                ip.extend_from_slice(&[0x60, 0, 0, 0]);
+                ip.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+                ip.extend_from_slice(&[6, 64]);
+                ip.extend_from_slice(&src.octets());
+                ip.extend_from_slice(&dst.octets());
+            }
+            _ => unreachable!("families are unified in Leg::new"),
+        }
+
+        let sum = checksum(&[&pseudo, &tcp]);
+        tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+
+        ip.extend_from_slice(&tcp);
+        ip
+    }
+}
+
+/// Internet checksum, every part but the last must have an even length.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u64;
+    for part in parts {
+        for chunk in part.chunks(2) {
+            sum += u64::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]));
+        }
+    }
+
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let padding = (4 - body.len() % 4) % 4;
+    let len = (12 + body.len() + padding) as u32;
+
+    let mut buf = Vec::with_capacity(len as usize);
+    buf.extend_from_slice(&kind.to_le_bytes());
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(body);
+    buf.resize(buf.len() + padding, 0);
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf
+}
+
+/// Timestamps are in microseconds, the default resolution.
+fn enhanced_packet(timestamp: u64, packet: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(20 + packet.len());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(packet);
+
+    block(ENHANCED_PACKET, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+    }
+
+    fn pseudo_header(packet: &[u8]) -> Vec<u8> {
+        let tcp_len = packet.len() - 20;
+        let mut pseudo = packet[12..20].to_vec();
+        pseudo.extend_from_slice(&[0, 6]);
+        pseudo.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+        pseudo
+    }
+
+    #[test]
+    fn blocks_are_padded_and_framed_by_their_length() {
+        for len in 0..8 {
+            let buf = block(ENHANCED_PACKET, &vec![0xaa; len]);
+
+            assert_eq!(buf.len() % 4, 0);
+            assert_eq!(u32_at(&buf, 0), ENHANCED_PACKET);
+            assert_eq!(u32_at(&buf, 4) as usize, buf.len());
+            assert_eq!(u32_at(&buf, buf.len() - 4) as usize, buf.len());
+            assert!(buf[8 + len..buf.len() - 4].iter().all(|b| *b == 0));
+        }
+    }
+
+    #[test]
+    fn enhanced_packets_carry_timestamp_and_lengths() {
+        let timestamp = 0x0001_0002_0003_0004;
+        let buf = enhanced_packet(timestamp, b"abcde");
+
+        assert_eq!(u32_at(&buf, 8), 0);
+        assert_eq!(u32_at(&buf, 12), 0x0001_0002);
+        assert_eq!(u32_at(&buf, 16), 0x0003_0004);
+        assert_eq!(u32_at(&buf, 20), 5);
+        assert_eq!(u32_at(&buf, 24), 5);
+        assert_eq!(&buf[28..33], b"abcde");
+        assert_eq!(buf.len(), 40);
+    }
+
+    #[test]
+    fn ipv4_packets_have_valid_checksums() {
+        let mut leg = Leg::new(
+            "10.0.0.1:40000".parse().unwrap(),
+            "10.0.0.2:443".parse().unwrap(),
+        );
+        // An odd payload length exercises the padding of the last word.
+        let packet = leg.packet(0, TCP_PSH | TCP_ACK, b"hello");
+
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(
+            u16::from_be_bytes([packet[2], packet[3]]) as usize,
+            packet.len()
+        );
+        assert_eq!(checksum(&[&packet[..20]]), 0);
+        assert_eq!(checksum(&[&pseudo_header(&packet), &packet[20..]]), 0);
+        assert_eq!(&packet[40..], b"hello");
+    }
+
+    #[test]
+    fn ipv6_packets_have_valid_checksums() {
+        let mut leg = Leg::new(
+            "[fd00::1]:40000".parse().unwrap(),
+            "[fd00::2]:80".parse().unwrap(),
+        );
+        let packet = leg.packet(1, TCP_SYN | TCP_ACK, &[]);
+        let tcp = &packet[40..];
+
+        let mut pseudo = packet[8..40].to_vec();
+        pseudo.extend_from_slice(&(tcp.len() as u32).to_be_bytes());
+        pseudo.extend_from_slice(&[0, 0, 0, 6]);
+
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(
+            u16::from_be_bytes([packet[4], packet[5]]) as usize,
+            tcp.len()
+        );
+        assert_eq!(checksum(&[&pseudo, tcp]), 0);
+        // Sent by the server side, from port 80.
+        assert_eq!(&tcp[..2], &80u16.to_be_bytes());
+    }
+
+    #[test]
+    fn sequence_numbers_follow_flags_and_payload() {
+        let mut leg = Leg::new(
+            "10.0.0.1:40000".parse().unwrap(),
+            "10.0.0.2:443".parse().unwrap(),
+        );
+        let seq = |packet: &[u8]| u32::from_be_bytes(packet[24..28].try_into().unwrap());
+        let ack = |packet: &[u8]| u32::from_be_bytes(packet[28..32].try_into().unwrap());
+
+        let syn = leg.packet(0, TCP_SYN, &[]);
+        let syn_ack = leg.packet(1, TCP_SYN | TCP_ACK, &[]);
+        let data = leg.packet(0, TCP_PSH | TCP_ACK, b"abc");
+        let fin = leg.packet(0, TCP_FIN | TCP_ACK, &[]);
+        let reply = leg.packet(1, TCP_ACK, &[]);
+
+        assert_eq!((seq(&syn), ack(&syn)), (0, 0));
+        assert_eq!((seq(&syn_ack), ack(&syn_ack)), (0, 1));
+        assert_eq!((seq(&data), ack(&data)), (1, 1));
+        assert_eq!(seq(&fin), 4);
+        assert_eq!(ack(&reply), 5);
+        assert_eq!(leg.fin, [true, false]);
+    }
+
+    #[test]
+    fn files_start_with_section_and_interface_blocks() {
+        let path =
+            std::env::temp_dir().join(format!("koblas-capture-{}.pcapng", std::process::id()));
+        drop(Recorder::create(&path).unwrap());
+        let buf = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(u32_at(&buf, 0), SECTION_HEADER);
+        assert_eq!(u32_at(&buf, 8), BYTE_ORDER_MAGIC);
+        let shb_len = u32_at(&buf, 4) as usize;
+
+        let idb = &buf[shb_len..];
+        assert_eq!(u32_at(idb, 0), INTERFACE_DESCRIPTION);
+        assert_eq!(u16::from_le_bytes([idb[8], idb[9]]), LINKTYPE_RAW);
+        assert_eq!(shb_len + u32_at(idb, 4) as usize, buf.len());
+    }
+}
@@ -0,0 +1,135 @@
+//! Fault injection for testing clients against a degraded network: latency, jitter,
+//! throughput caps, resets and slow or refused connects.
+
+use crate::config::Chaos;
+use net5::error::SocksError;
+use net5::proto::CONNECTION_REFUSED_REPLY;
+use rand_core::{OsRng, RngCore};
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time;
+use tracing::warn;
+
+fn random() -> u64 {
+    OsRng.next_u64()
+}
+
+/// `true` with `probability`, always for 1 and never for 0.
+fn chance(probability: f64) -> bool {
+    // The top 53 bits make an evenly spread f64 in [0, 1).
+    let roll = (random() >> 11) as f64 / (1u64 << 53) as f64;
+    roll < probability
+}
+
+impl Chaos {
+    pub fn select<'a>(rules: &'a [Chaos], domain: Option<&str>, ip: IpAddr) -> Option<&'a Chaos> {
+        rules
+            .iter()
+            .find(|rule| rule.dest.iter().any(|dest| dest.matches(domain, Some(ip))))
+    }
+
+    /// Waits out `connect_delay` and then maybe refuses the connect.
+    pub async fn connect(&self) -> Result<(), SocksError> {
+        time::sleep(Duration::from_millis(self.connect_delay)).await;
+
+        if chance(self.refuse) {
+            warn!("chaos refused the connect");
+            return Err(SocksError::Reply(CONNECTION_REFUSED_REPLY));
+        }
+
+        Ok(())
+    }
+
+    /// Delay for the next chunk, `latency` plus or minus up to `jitter`.
+    pub fn delay(&self) -> Duration {
+        let span = self.jitter.saturating_mul(2).saturating_add(1);
+        let offset = random() % span;
+
+        let millis = match offset.checked_sub(self.jitter) {
+            Some(more) => self.latency.saturating_add(more),
+            None => self.latency.saturating_sub(self.jitter - offset),
+        };
+        Duration::from_millis(millis)
+    }
+
+    /// Time one byte takes at the configured rate.
+    pub fn byte_time(&self) -> Option<Duration> {
+        self.rate
+            .filter(|rate| *rate > 0)
+            .map(|rate| Duration::from_secs(1) / rate.min(u32::MAX as u64) as u32)
+    }
+
+    /// Rolls the dice for a reset before the next chunk.
+    pub fn reset(&self) -> io::Result<()> {
+        if chance(self.reset) {
+            warn!("chaos reset the session");
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "reset by chaos mode",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chaos(latency: u64, jitter: u64, rate: Option<u64>) -> Chaos {
+        Chaos {
+            dest: Vec::new(),
+            latency,
+            jitter,
+            rate,
+            reset: 0.0,
+            connect_delay: 0,
+            refuse: 0.0,
+        }
+    }
+
+    #[test]
+    fn delay_stays_within_jitter() {
+        let rule = chaos(100, 20, None);
+        let range = Duration::from_millis(80)..=Duration::from_millis(120);
+
+        for _ in 0..1000 {
+            let delay = rule.delay();
+            assert!(range.contains(&delay), "{delay:?}");
+        }
+        assert_eq!(chaos(100, 0, None).delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn delay_saturates() {
+        for _ in 0..100 {
+            assert!(chaos(5, 20, None).delay() <= Duration::from_millis(25));
+            chaos(u64::MAX, u64::MAX, None).delay();
+            chaos(0, u64::MAX, None).delay();
+        }
+    }
+
+    #[test]
+    fn certain_chances_are_deterministic() {
+        for _ in 0..10_000 {
+            assert!(!chance(0.0));
+            assert!(chance(1.0));
+        }
+    }
+
+    #[test]
+    fn byte_time_scales_with_rate() {
+        assert_eq!(
+            chaos(0, 0, Some(1000)).byte_time(),
+            Some(Duration::from_millis(1))
+        );
+        assert_eq!(
+            chaos(0, 0, Some(4000)).byte_time(),
+            Some(Duration::from_micros(250))
+        );
+        assert_eq!(chaos(0, 0, Some(0)).byte_time(), None);
+        assert_eq!(chaos(0, 0, None).byte_time(), None);
+    }
+}
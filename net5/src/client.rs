@@ -0,0 +1,284 @@
+use crate::error::{Error, Result};
+use crate::proto::*;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// SOCKS5 client, one proxy connection per request.
+#[derive(Clone, Debug)]
+pub struct Client {
+    proxy: String,
+    credentials: Option<Credentials>,
+}
+
+impl Client {
+    pub fn new(proxy: impl Into<String>) -> Self {
+        Self {
+            proxy: proxy.into(),
+            credentials: None,
+        }
+    }
+
+    pub fn with_credentials(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.credentials = Some(Credentials {
+            username: username.into(),
+            password: password.into(),
+        });
+        self
+    }
+
+    async fn open(&self) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(self.proxy.as_str()).await?;
+        handshake(&mut stream, self.credentials.as_ref()).await?;
+
+        Ok(stream)
+    }
+
+    pub async fn connect(&self, target: &Target) -> Result<TcpStream> {
+        let mut stream = self.open().await?;
+        request(&mut stream, CONNECT_COMMAND, target).await?;
+
+        Ok(stream)
+    }
+
+    /// Asks the proxy to listen for a single inbound connection from `target`.
+    pub async fn bind(&self, target: &Target) -> Result<Bind> {
+        let mut stream = self.open().await?;
+        let addr = request(&mut stream, BIND_COMMAND, target).await?;
+
+        Ok(Bind { stream, addr })
+    }
+
+    pub async fn udp_associate(&self) -> Result<UdpAssociation> {
+        let mut control = self.open().await?;
+        let proxy = control.peer_addr()?;
+
+        let unspecified = match proxy {
+            SocketAddr::V4(_) => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = UdpSocket::bind((unspecified, 0)).await?;
+        let local = Target::Addr(SocketAddr::new(unspecified, socket.local_addr()?.port()));
+
+        let relay = match request(&mut control, UDP_ASSOCIATE_COMMAND, &local).await? {
+            Target::Addr(addr) if addr.ip().is_unspecified() => {
+                SocketAddr::new(proxy.ip(), addr.port())
+            }
+            Target::Addr(addr) => addr,
+            Target::Domain(domain, port) => tokio::net::lookup_host((domain.as_str(), port))
+                .await?
+                .next()
+                .ok_or_else(|| Error::InvalidTarget(format!("{domain}:{port}")))?,
+        };
+        socket.connect(relay).await?;
+
+        Ok(UdpAssociation { control, socket })
+    }
+
+    /// Looks `domain` up on the proxy without connecting anywhere.
+    pub async fn resolve(&self, domain: &str) -> Result<IpAddr> {
+        let mut stream = self.open().await?;
+        let target = Target::Domain(domain.to_owned(), 0);
+
+        match request(&mut stream, RESOLVE_COMMAND, &target).await? {
+            Target::Addr(addr) => Ok(addr.ip()),
+            Target::Domain(domain, _) => Err(Error::InvalidTarget(domain)),
+        }
+    }
+
+    /// Reverse lookup of `ip` on the proxy.
+    pub async fn resolve_ptr(&self, ip: IpAddr) -> Result<String> {
+        let mut stream = self.open().await?;
+        let target = Target::Addr(SocketAddr::new(ip, 0));
+
+        match request(&mut stream, RESOLVE_PTR_COMMAND, &target).await? {
+            Target::Domain(name, _) => Ok(name),
+            Target::Addr(addr) => Err(Error::InvalidTarget(addr.to_string())),
+        }
+    }
+}
+
+pub struct Bind {
+    stream: TcpStream,
+    addr: Target,
+}
+
+impl Bind {
+    /// Address the proxy listens on, to be handed to the remote side.
+    pub fn addr(&self) -> &Target {
+        &self.addr
+    }
+
+    /// Waits for the second reply and returns the relayed stream with the remote address.
+    pub async fn accept(mut self) -> Result<(TcpStream, Target)> {
+        let peer = read_reply(&mut self.stream).await?;
+
+        Ok((self.stream, peer))
+    }
+}
+
+/// UDP relay session; it lives as long as the control connection stays open.
+pub struct UdpAssociation {
+    control: TcpStream,
+    socket: UdpSocket,
+}
+
+impl UdpAssociation {
+    pub fn relay_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.peer_addr()?)
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: &Target) -> Result<usize> {
+        let mut packet = vec![0, 0, 0];
+        target.encode(&mut packet)?;
+
+        let header = packet.len();
+        packet.extend_from_slice(buf);
+
+        let sent = self.socket.send(&packet).await?;
+        Ok(sent.saturating_sub(header))
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Target)> {
+        let mut packet = vec![0u8; buf.len() + 262];
+
+        loop {
+            let len = self.socket.recv(&mut packet).await?;
+            let mut payload = &packet[..len];
+
+            let mut header = [0u8; 3];
+            payload.read_exact(&mut header).await?;
+            // Fragments are optional in RFC 1928 and may be dropped.
+            if header[2] != 0 {
+                continue;
+            }
+
+            let target = Target::read_from(&mut payload).await?;
+            let len = payload.len().min(buf.len());
+            buf[..len].copy_from_slice(&payload[..len]);
+
+            return Ok((len, target));
+        }
+    }
+
+    pub fn into_parts(self) -> (TcpStream, UdpSocket) {
+        (self.control, self.socket)
+    }
+}
+
+/// Negotiates a method and, when the proxy picks it, authenticates per RFC 1929.
+pub async fn handshake<S>(stream: &mut S, credentials: Option<&Credentials>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[SOCKS_VERSION, 2, NO_AUTH_METHOD, AUTH_METHOD],
+        None => &[SOCKS_VERSION, 1, NO_AUTH_METHOD],
+    };
+    stream.write_all(greeting).await?;
+    stream.flush().await?;
+
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).await?;
+
+    let ver = buf[0];
+    if ver != SOCKS_VERSION {
+        return Err(Error::InvalidVersion {
+            expected: SOCKS_VERSION,
+            found: ver,
+        });
+    }
+
+    match (buf[1], credentials) {
+        (NO_AUTH_METHOD, _) => Ok(()),
+        (AUTH_METHOD, Some(credentials)) => authenticate(stream, credentials).await,
+        _ => Err(Error::MethodNotFound),
+    }
+}
+
+async fn authenticate<S>(stream: &mut S, credentials: &Credentials) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let username = credentials.username.as_bytes();
+    let password = credentials.password.as_bytes();
+    let (Ok(ulen), Ok(plen)) = (u8::try_from(username.len()), u8::try_from(password.len())) else {
+        return Err(Error::AuthFailed);
+    };
+
+    let mut buf = vec![AUTH_VERSION, ulen];
+    buf.extend_from_slice(username);
+    buf.push(plen);
+    buf.extend_from_slice(password);
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+
+    // The proxy closes without a verdict when it can't check credentials at the moment.
+    let mut buf = [0u8; 2];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => Error::AuthUnavailable(err),
+            _ => err.into(),
+        })?;
+
+    let ver = buf[0];
+    if ver != AUTH_VERSION {
+        return Err(Error::InvalidVersion {
+            expected: AUTH_VERSION,
+            found: ver,
+        });
+    }
+
+    match buf[1] {
+        AUTH_SUCCESS => Ok(()),
+        _ => Err(Error::AuthFailed),
+    }
+}
+
+/// Sends a request and returns the bound address from the proxy's reply.
+pub async fn request<S>(stream: &mut S, cmd: u8, target: &Target) -> Result<Target>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = vec![SOCKS_VERSION, cmd, 0];
+    target.encode(&mut buf)?;
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+
+    read_reply(stream).await
+}
+
+async fn read_reply<S>(stream: &mut S) -> Result<Target>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await?;
+
+    let ver = buf[0];
+    if ver != SOCKS_VERSION {
+        return Err(Error::InvalidVersion {
+            expected: SOCKS_VERSION,
+            found: ver,
+        });
+    }
+
+    let addr = Target::read_addr(stream, buf[3]).await?;
+    match buf[1] {
+        SUCCESS_REPLY => Ok(addr),
+        reply => Err(Error::Reply(reply)),
+    }
+}
@@ -1,25 +1,423 @@
+use crate::auth::constant_time_eq;
+use crate::pattern::{Cidr, Pattern};
+use crate::proxy_protocol::Version;
+use crate::rewrite::Destination;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use color_eyre::eyre::WrapErr;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
 use std::{fs, str};
 use toml::de;
 
 #[derive(Default, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
+    #[serde(default)]
+    pub server: Server,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub log: Log,
     #[serde(default)]
     pub users: BTreeMap<String, String>,
+    #[serde(default)]
+    pub auth: Auth,
+    #[serde(default)]
+    pub egress: Egress,
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocol,
+    #[serde(default)]
+    pub ssrf: Ssrf,
+    #[serde(default)]
+    pub capture: Capture,
+    #[serde(default)]
+    pub sniff: Sniff,
+    #[serde(default)]
+    pub rewrite: Vec<Rewrite>,
+    #[serde(default)]
+    pub socket: Socket,
+    #[serde(default)]
+    pub chaos: Vec<Chaos>,
+    #[serde(default)]
+    pub ws: Ws,
+    #[serde(default)]
+    pub tunnel: Tunnel,
+}
+
+/// Settings that `KOBLAS_*` variables and command line flags override, in this order.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Server {
+    #[serde(default = "default_addr")]
+    pub addr: IpAddr,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Addresses to listen on instead of `addr` and `port`, which the flags and variables
+    /// bring back.
+    #[serde(default)]
+    pub listen: Vec<SocketAddr>,
+    /// Clients served at once, more are disconnected right away.
+    #[serde(default = "default_limit")]
+    pub limit: i32,
+    /// Keeps client addresses out of the logs.
+    #[serde(default)]
+    pub anon: bool,
+    /// Relay with `splice(2)` instead of user-space buffers, Linux only.
+    #[serde(default)]
+    pub splice: bool,
+    /// pcapng file for the sessions matching `[capture]`.
+    pub capture: Option<PathBuf>,
+    /// Directory for the daily per-user traffic totals.
+    pub accounting: Option<PathBuf>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            addr: default_addr(),
+            port: default_port(),
+            listen: Vec::new(),
+            limit: default_limit(),
+            anon: false,
+            splice: false,
+            capture: None,
+            accounting: None,
+        }
+    }
+}
+
+impl Server {
+    pub fn listeners(&self) -> Vec<SocketAddr> {
+        if self.listen.is_empty() {
+            vec![SocketAddr::new(self.addr, self.port)]
+        } else {
+            self.listen.clone()
+        }
+    }
+}
+
+/// All in seconds, unset means no limit.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Timeouts {
+    /// From accepting a client until its request is answered.
+    pub handshake: Option<u64>,
+    /// Connecting to the destination.
+    pub connect: Option<u64>,
+}
+
+impl Timeouts {
+    pub fn handshake(&self) -> Option<Duration> {
+        self.handshake.map(Duration::from_secs)
+    }
+
+    pub fn connect(&self) -> Option<Duration> {
+        self.connect.map(Duration::from_secs)
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Log {
+    /// `tracing` filter like `info` or `warn,koblas=debug`, `RUST_LOG` takes precedence.
+    pub level: Option<String>,
+}
+
+/// Credential sources consulted, in this order, for users missing from `users`.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Auth {
+    /// Apache htpasswd file with bcrypt, apr1, SHA-1 or argon2 entries, read on every lookup.
+    pub htpasswd: Option<PathBuf>,
+    /// Program reading the username and password as lines on stdin, exit status 0 accepts
+    /// and 1 rejects, anything else is a failure.
+    #[serde(default)]
+    pub command: Vec<String>,
+    /// URL receiving a JSON POST with `username` and `password`, 2xx accepts and
+    /// 401 or 403 rejects.
+    pub webhook: Option<String>,
+    /// Seconds a backend's verdict is reused, 0 disables caching.
+    #[serde(default = "default_cache_ttl")]
+    pub cache_ttl: u64,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self {
+            htpasswd: None,
+            command: Vec::new(),
+            webhook: None,
+            cache_ttl: default_cache_ttl(),
+        }
+    }
+}
+
+fn default_cache_ttl() -> u64 {
+    60
+}
+
+/// Where outbound connections leave from, globally, per user or per destination.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Egress {
+    #[serde(default)]
+    pub default: Binding,
+    #[serde(default)]
+    pub users: BTreeMap<String, Binding>,
+    #[serde(default)]
+    pub rules: Vec<EgressRule>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Binding {
+    /// Source addresses, the first one of the destination's family is used
+    /// unless `round_robin` is set.
+    #[serde(default)]
+    pub source: Vec<IpAddr>,
+    #[serde(default)]
+    pub round_robin: bool,
+    /// Device name for `SO_BINDTODEVICE`, Linux only.
+    pub interface: Option<String>,
+    #[serde(skip)]
+    pub next: AtomicUsize,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EgressRule {
+    pub dest: Vec<Pattern>,
+    pub via: Binding,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyProtocol {
+    /// Peers that must prefix their connections with a PROXY header, e.g. load balancers.
+    #[serde(default)]
+    pub trusted: Vec<Cidr>,
+    /// Backends that expect a PROXY header carrying the client address.
+    #[serde(default)]
+    pub upstream: Vec<ProxyUpstream>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyUpstream {
+    pub dest: Vec<Pattern>,
+    #[serde(default)]
+    pub version: Version,
+}
+
+/// Destinations refused after resolution: loopback, link-local, multicast,
+/// unspecified and, with `private`, RFC 1918 and unique local ranges.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ssrf {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub private: bool,
+    /// Destinations exempt from the checks above.
+    #[serde(default)]
+    pub allow: Vec<Pattern>,
+}
+
+impl Default for Ssrf {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            private: false,
+            allow: Vec::new(),
+        }
+    }
+}
+
+/// Sessions recorded by `--capture`, every non-empty filter has to match.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Capture {
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub clients: Vec<Cidr>,
+    #[serde(default)]
+    pub dest: Vec<Pattern>,
+}
+
+/// Server name taken from the first client bytes of IP-literal requests, that is
+/// the TLS SNI or the HTTP `Host` header.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sniff {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Names whose sessions are closed before anything reaches the peer, and that RESOLVE
+    /// doesn't look up.
+    #[serde(default)]
+    pub deny: Vec<Pattern>,
+    /// Relays sessions whose first bytes give no name while `deny` is set: the client
+    /// stayed silent, the server spoke first or the protocol isn't TLS or HTTP. These are
+    /// refused by default, since a client could otherwise dodge `deny` that way.
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+/// Requested destination replaced before anything else sees it, the first matching rule wins.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rewrite {
+    pub dest: Vec<Pattern>,
+    /// Only requests for this port, any port if unset.
+    pub port: Option<u16>,
+    pub to: Destination,
+}
+
+/// Options for the accepted client sockets and the outbound connections,
+/// anything unset keeps the system default.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Socket {
+    #[serde(default)]
+    pub client: SocketOptions,
+    #[serde(default)]
+    pub outbound: SocketOptions,
+    /// TCP Fast Open queue length of the listener, Linux only.
+    pub fast_open: Option<u32>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SocketOptions {
+    pub nodelay: Option<bool>,
+    pub keepalive: Option<Keepalive>,
+    pub send_buffer: Option<usize>,
+    pub recv_buffer: Option<usize>,
+    /// `IP_TOS` or, on IPv6, the traffic class; DSCP takes the upper six bits, e.g. 0xb8 for EF.
+    pub tos: Option<u8>,
+    /// `SO_MARK` for policy routing, Linux only.
+    pub mark: Option<u32>,
+}
+
+/// Enables `SO_KEEPALIVE`, all times are in seconds.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Keepalive {
+    pub idle: u64,
+    /// Linux only, like `count`.
+    pub interval: Option<u64>,
+    pub count: Option<u32>,
+}
+
+/// Degraded network for testing clients, the first rule matching the destination applies.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Chaos {
+    pub dest: Vec<Pattern>,
+    /// Milliseconds added to every chunk, in both directions.
+    #[serde(default)]
+    pub latency: u64,
+    /// Up to this many milliseconds more or less than `latency`, drawn per chunk.
+    #[serde(default)]
+    pub jitter: u64,
+    /// Bytes per second, in each direction.
+    pub rate: Option<u64>,
+    /// Probability per chunk that the session is reset.
+    #[serde(default)]
+    pub reset: f64,
+    /// Milliseconds waited before connecting.
+    #[serde(default)]
+    pub connect_delay: u64,
+    /// Probability that a connect is refused.
+    #[serde(default)]
+    pub refuse: f64,
+}
+
+/// WebSocket transport of `ws-local` and `ws-remote`.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ws {
+    /// URL of the `ws-remote` instance, `wss://` for TLS.
+    pub remote: Option<String>,
+    /// Shared secret sent by `ws-local` and required by `ws-remote`.
+    pub token: Option<String>,
+    /// PEM certificate chain of `ws-remote`, enables TLS together with `tls_key`.
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of `ws-remote`.
+    pub tls_key: Option<PathBuf>,
+}
+
+/// Multiplexed tunnel between `entry` and `exit`.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tunnel {
+    /// Address of the exit as host:port, for `entry`.
+    pub exit: Option<String>,
+    /// Shared secret sent by `entry` and required by `exit`.
+    pub token: Option<String>,
+}
+
+fn default_addr() -> IpAddr {
+    IpAddr::from([127, 0, 0, 1])
+}
+
+fn default_port() -> u16 {
+    1080
+}
+
+fn default_limit() -> i32 {
+    127
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Sniff {
+    pub fn denies(&self, name: &str) -> bool {
+        self.deny.iter().any(|deny| deny.matches_domain(name))
+    }
 }
 
 impl Config {
+    /// Whether clients have to authenticate, either against `users` or a backend.
+    pub fn auth_required(&self) -> bool {
+        !self.users.is_empty()
+            || self.auth.htpasswd.is_some()
+            || !self.auth.command.is_empty()
+            || self.auth.webhook.is_some()
+    }
+
     pub fn from_path(path: impl AsRef<Path>) -> color_eyre::Result<Self> {
         let path = path.as_ref();
 
-        let buf = fs::read(path)?;
-        let str = str::from_utf8(&buf)?;
+        let load = || -> color_eyre::Result<Self> {
+            let buf = fs::read(path)?;
+            let str = str::from_utf8(&buf)?;
+
+            Ok(Self::from_str(str)?)
+        };
+
+        load().wrap_err_with(|| format!("failed loading {}", path.display()))
+    }
+
+    /// Checks a password against the stored argon2 hash or, failing to parse one, plain text.
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let Some(stored) = self.users.get(user) else {
+            return false;
+        };
 
-        Ok(Self::from_str(str)?)
+        match PasswordHash::new(stored) {
+            Ok(hash) => Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+            Err(_) => constant_time_eq(stored.as_bytes(), password.as_bytes()),
+        }
     }
 }
 
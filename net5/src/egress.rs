@@ -0,0 +1,110 @@
+use crate::config::{Binding, Egress, SocketOptions};
+use socket2::SockRef;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use tokio::net::{TcpSocket, TcpStream};
+
+impl Egress {
+    /// Picks the binding for a destination: the first matching rule, then the user's, then the default.
+    pub fn select(&self, user: Option<&str>, domain: Option<&str>, ip: IpAddr) -> &Binding {
+        self.rules
+            .iter()
+            .find(|rule| rule.dest.iter().any(|dest| dest.matches(domain, Some(ip))))
+            .map(|rule| &rule.via)
+            .or_else(|| user.and_then(|user| self.users.get(user)))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl Binding {
+    fn source(&self, dest: IpAddr) -> io::Result<Option<IpAddr>> {
+        if self.source.is_empty() {
+            return Ok(None);
+        }
+
+        let pool: Vec<_> = self
+            .source
+            .iter()
+            .filter(|source| source.is_ipv4() == dest.is_ipv4())
+            .collect();
+        if pool.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no source address configured for {dest}"),
+            ));
+        }
+
+        let idx = if self.round_robin {
+            self.next.fetch_add(1, Ordering::Relaxed) % pool.len()
+        } else {
+            0
+        };
+
+        Ok(Some(*pool[idx]))
+    }
+
+    pub async fn connect(
+        &self,
+        addr: SocketAddr,
+        options: &SocketOptions,
+    ) -> io::Result<TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        // Before connecting, so that buffer sizes count for the window scale in the SYN.
+        options.apply(SockRef::from(&socket), addr.is_ipv6())?;
+
+        if let Some(interface) = &self.interface {
+            bind_device(&socket, interface)?;
+        }
+        if let Some(source) = self.source(addr.ip())? {
+            socket.bind(SocketAddr::new(source, 0))?;
+        }
+
+        socket.connect(addr).await
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_: &TcpSocket, interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("binding to interface {interface} is not supported on this platform"),
+    ))
+}
+
+/// Tries every resolved address in order, like `TcpStream::connect`, honouring egress bindings.
+pub async fn connect(
+    egress: &Egress,
+    options: &SocketOptions,
+    user: Option<&str>,
+    domain: Option<&str>,
+    dest: &[SocketAddr],
+) -> io::Result<TcpStream> {
+    let mut last_err = None;
+
+    for &addr in dest {
+        match egress
+            .select(user, domain, addr.ip())
+            .connect(addr, options)
+            .await
+        {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
@@ -1,3 +1,4 @@
+use crate::proto;
 use itertools::Itertools;
 use std::fmt::{Display, Formatter};
 use std::string::FromUtf8Error;
@@ -7,9 +8,13 @@ pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
+    AuthFailed,
+    AuthUnavailable(io::Error),
+    InvalidTarget(String),
     InvalidVersion { expected: u8, found: u8 },
     Io(io::Error),
     MethodNotFound,
+    Reply(u8),
     Socks(SocksError),
 }
 
@@ -18,6 +23,9 @@ impl error::Error for Error {}
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
+            Self::AuthFailed => write!(f, "authentication failed"),
+            Self::AuthUnavailable(err) => write!(f, "authentication backend failed: {err}"),
+            Self::InvalidTarget(target) => write!(f, "invalid target `{target}`"),
             Self::InvalidVersion { expected, found } => {
                 write!(
                     f,
@@ -26,6 +34,9 @@ impl Display for Error {
             }
             Self::Io(err) => err.fmt(f),
             Self::MethodNotFound => write!(f, "method not found"),
+            Self::Reply(reply) => {
+                write!(f, "proxy replied {reply:#x} ({})", proto::reply_message(*reply))
+            }
             Self::Socks(err) => err.fmt(f),
         }
     }
@@ -48,6 +59,9 @@ pub enum SocksError {
     InvalidAddr { expected: Vec<u8>, found: u8 },
     InvalidCommand { expected: u8, found: u8 },
     Io(io::Error),
+    NotAllowed,
+    Reply(u8),
+    Unresolved(String, io::Error),
     Utf8(FromUtf8Error),
 }
 
@@ -65,6 +79,11 @@ impl Display for SocksError {
                 write!(f, "invalid command (expected {expected}, found {found})")
             }
             Self::Io(err) => err.fmt(f),
+            Self::NotAllowed => write!(f, "destination not allowed"),
+            Self::Reply(reply) => {
+                write!(f, "remote replied {reply:#x} ({})", proto::reply_message(*reply))
+            }
+            Self::Unresolved(name, err) => write!(f, "failed resolving {name}: {err}"),
             Self::Utf8(err) => err.fmt(f),
         }
     }
@@ -1,134 +1,734 @@
+mod accounting;
+mod auth;
+mod capture;
+mod chaos;
 mod config;
-mod error;
+mod egress;
+mod pattern;
+mod proxy_protocol;
+mod relay;
+mod resolve;
+mod rewrite;
+mod sniff;
+mod sockopt;
+mod ssrf;
+mod systemd;
+mod tunnel;
+mod ws;
 
-use crate::config::Config;
-use crate::error::{Error, SocksError};
-use clap::{Parser};
+use crate::config::{Chaos, Config};
+use crate::relay::{Stream, Tap};
+use clap::builder::BoolishValueParser;
+use clap::{Args, Parser, Subcommand};
+use color_eyre::eyre::{bail, WrapErr};
+use net5::client::Client;
+use net5::error::{self, Error, SocksError};
+use net5::proto::*;
+use socket2::SockRef;
+use std::future;
 use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicI32, Ordering};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::task::Poll;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Builder;
+use tokio::time::{self, Interval};
+use tokio_rustls::TlsAcceptor;
 use tokio::{io, net};
 use tracing::{debug, error, error_span, field, info, warn, Instrument, Span};
+use tracing_subscriber::EnvFilter;
 
 #[derive(Debug, Parser)]
 struct Cli {
-    #[arg(short, long, env = "KOBLAS_ADDRESS", default_value_t = IpAddr::from([127, 0, 0, 1]))]
-    addr: IpAddr,
-    #[arg(short, long, env = "KOBLAS_PORT", default_value_t = 1080)]
-    port: u16,
-    #[arg(short, long, env = "KOBLAS_LIMIT", default_value_t = 127)]
-    limit: i32,
-    #[arg(long, env = "KOBLAS_ANONYMIZATION")]
-    anon: bool,
-    #[arg(short, long, env = "KOBLAS_USERS_PATH", value_name = "FILE")]
-    users: Option<PathBuf>,
-}
-
-fn install_tracing() {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// TOML configuration, the flags below and their variables take precedence over it
+    #[arg(
+        short,
+        long,
+        env = "KOBLAS_USERS_PATH",
+        value_name = "FILE",
+        visible_alias = "users",
+        visible_short_alias = 'u'
+    )]
+    config: Option<PathBuf>,
+    /// Replaces the `listen` addresses of the file [default: 127.0.0.1]
+    #[arg(short, long, env = "KOBLAS_ADDRESS")]
+    addr: Option<IpAddr>,
+    /// Replaces the `listen` addresses of the file [default: 1080]
+    #[arg(short, long, env = "KOBLAS_PORT")]
+    port: Option<u16>,
+    /// [default: 127]
+    #[arg(short, long, env = "KOBLAS_LIMIT")]
+    limit: Option<i32>,
+    #[arg(
+        long,
+        env = "KOBLAS_ANONYMIZATION",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new()
+    )]
+    anon: Option<bool>,
+    /// Relay with splice(2) instead of user-space buffers (Linux only)
+    #[arg(
+        long,
+        env = "KOBLAS_SPLICE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new()
+    )]
+    splice: Option<bool>,
+    /// Record sessions matching the `[capture]` filters to a pcapng file
+    #[arg(long, env = "KOBLAS_CAPTURE", value_name = "FILE")]
+    capture: Option<PathBuf>,
+    /// Directory for the daily per-user traffic totals, read by `report`
+    #[arg(long, env = "KOBLAS_ACCOUNTING", value_name = "DIR")]
+    accounting: Option<PathBuf>,
+    /// Log filter like `info` or `warn,koblas=debug` [default: error]
+    #[arg(long, env = "RUST_LOG", value_name = "FILTER")]
+    log: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Connect to a target through a SOCKS5 proxy, piping it to stdin and stdout
+    Connect(ConnectArgs),
+    /// Accept SOCKS clients and carry each session over WebSocket to a `ws-remote` instance
+    WsLocal(WsLocalArgs),
+    /// Serve SOCKS sessions tunnelled over WebSocket by `ws-local` instances
+    WsRemote(WsRemoteArgs),
+    /// Accept SOCKS clients and multiplex their sessions over one connection to an exit
+    Entry(EntryArgs),
+    /// Serve multiplexed sessions for `entry` instances
+    Exit(ExitArgs),
+    /// Print traffic totals from the `--accounting` directory
+    Report(ReportArgs),
+    /// Validate the `--config` file, with the flags and variables applied, and exit
+    CheckConfig,
+}
+
+#[derive(Debug, Args)]
+struct ConnectArgs {
+    /// Proxy address as host:port
+    proxy: String,
+    /// Target address as host:port
+    target: Target,
+    #[arg(long, env = "KOBLAS_CONNECT_USER", requires = "password")]
+    user: Option<String>,
+    #[arg(long, env = "KOBLAS_CONNECT_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct WsLocalArgs {
+    /// Remote URL, `wss://` for TLS [config: ws.remote]
+    remote: Option<String>,
+    /// Shared secret expected by the remote instance [config: ws.token]
+    #[arg(long, env = "KOBLAS_WS_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct WsRemoteArgs {
+    /// PEM certificate chain, enables TLS [config: ws.tls_cert]
+    #[arg(long, value_name = "FILE")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key [config: ws.tls_key]
+    #[arg(long, value_name = "FILE")]
+    tls_key: Option<PathBuf>,
+    /// Shared secret required from local instances [config: ws.token]
+    #[arg(long, env = "KOBLAS_WS_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct EntryArgs {
+    /// Exit address as host:port [config: tunnel.exit]
+    exit: Option<String>,
+    /// Shared secret expected by the exit [config: tunnel.token]
+    #[arg(long, env = "KOBLAS_TUNNEL_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct ExitArgs {
+    /// Shared secret required from entries [config: tunnel.token]
+    #[arg(long, env = "KOBLAS_TUNNEL_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+}
+
+#[derive(Debug, Args)]
+struct ReportArgs {
+    /// First day, as YYYY-MM-DD in UTC
+    #[arg(long)]
+    from: Option<accounting::Date>,
+    /// Last day, today if unset
+    #[arg(long)]
+    to: Option<accounting::Date>,
+    /// Rows in the top users and destinations tables
+    #[arg(long, default_value_t = 10)]
+    top: usize,
+}
+
+struct State {
+    cli: Cli,
+    config: Config,
+    auth: auth::Authenticator,
+    tls: Option<TlsAcceptor>,
+    entry: Option<tunnel::Entry>,
+    capture: Option<capture::Recorder>,
+    accounting: Option<accounting::Accounting>,
+    notifier: Option<systemd::Notifier>,
+}
+
+impl State {
+    /// Passes `state` on to the service manager if it asked for notifications.
+    fn notify(&self, state: &str) {
+        if let Some(notifier) = &self.notifier {
+            if let Err(err) = notifier.notify(state) {
+                warn!("failed notifying the service manager: {err}");
+            }
+        }
+    }
+
+    fn status(&self, clients: i32) {
+        self.notify(&format!("STATUS={clients} active clients"));
+    }
+}
+
+enum Peer {
+    Direct(TcpStream),
+    Tunnel(tunnel::Channel),
+}
+
+enum Outcome {
+    Connected(Target, Peer),
+    /// Answer to a RESOLVE or RESOLVE_PTR, carried in the reply's address.
+    Resolved(Target),
+}
+
+fn log_filter(level: Option<&str>) -> color_eyre::Result<EnvFilter> {
+    match level {
+        Some(level) => {
+            EnvFilter::try_new(level).wrap_err_with(|| format!("invalid log filter `{level}`"))
+        }
+        None => Ok(EnvFilter::default()),
+    }
+}
+
+fn install_tracing(filter: EnvFilter) {
     use tracing_error::ErrorLayer;
+    use tracing_subscriber::fmt;
     use tracing_subscriber::prelude::*;
-    use tracing_subscriber::{fmt, EnvFilter};
 
     tracing_subscriber::registry()
-        .with(fmt::layer().with_target(false))
-        .with(EnvFilter::from_default_env())
+        .with(fmt::layer().with_target(false).with_writer(std::io::stderr))
+        .with(filter)
         .with(ErrorLayer::default())
         .init();
 }
 
+/// Layers the variables and flags, which clap has already merged, over the file.
+fn apply_overrides(cli: &Cli, config: &mut Config) {
+    let server = &mut config.server;
+
+    if let Some(addr) = cli.addr {
+        server.addr = addr;
+        server.listen.clear();
+    }
+    if let Some(port) = cli.port {
+        server.port = port;
+        server.listen.clear();
+    }
+    if let Some(limit) = cli.limit {
+        server.limit = limit;
+    }
+    if let Some(anon) = cli.anon {
+        server.anon = anon;
+    }
+    if let Some(splice) = cli.splice {
+        server.splice = splice;
+    }
+    if let Some(capture) = &cli.capture {
+        server.capture = Some(capture.clone());
+    }
+    if let Some(accounting) = &cli.accounting {
+        server.accounting = Some(accounting.clone());
+    }
+    if let Some(log) = &cli.log {
+        config.log.level = Some(log.clone());
+    }
+
+    let (ws, tunnel) = (&mut config.ws, &mut config.tunnel);
+    match &cli.command {
+        Some(Command::WsLocal(args)) => {
+            override_with(&mut ws.remote, &args.remote);
+            override_with(&mut ws.token, &args.token);
+        }
+        Some(Command::WsRemote(args)) => {
+            override_with(&mut ws.tls_cert, &args.tls_cert);
+            override_with(&mut ws.tls_key, &args.tls_key);
+            override_with(&mut ws.token, &args.token);
+        }
+        Some(Command::Entry(args)) => {
+            override_with(&mut tunnel.exit, &args.exit);
+            override_with(&mut tunnel.token, &args.token);
+        }
+        Some(Command::Exit(args)) => override_with(&mut tunnel.token, &args.token),
+        _ => {}
+    }
+}
+
+fn override_with<T: Clone>(setting: &mut Option<T>, flag: &Option<T>) {
+    if let Some(flag) = flag {
+        *setting = Some(flag.clone());
+    }
+}
+
+/// Where the configuration came from, for error messages.
+fn source(cli: &Cli) -> String {
+    match &cli.config {
+        Some(path) if path.exists() => path.display().to_string(),
+        _ => "the flags and variables".to_owned(),
+    }
+}
+
+/// Checks what the file format itself can't express, naming the offending keys.
+fn validate(cli: &Cli, config: &Config) -> color_eyre::Result<()> {
+    if config.server.capture.is_some() && config.server.anon {
+        bail!("`server.capture` can't be used with `server.anon`");
+    }
+
+    let (ws, tunnel) = (&config.ws, &config.tunnel);
+    match &cli.command {
+        Some(Command::WsLocal(_)) if ws.remote.is_none() => {
+            bail!("ws-local needs `ws.remote` or the remote URL argument");
+        }
+        Some(Command::WsRemote(_)) if ws.tls_cert.is_some() != ws.tls_key.is_some() => {
+            bail!("`ws.tls_cert` and `ws.tls_key` must be set together");
+        }
+        Some(Command::Entry(_)) if tunnel.exit.is_none() => {
+            bail!("entry needs `tunnel.exit` or the exit address argument");
+        }
+        Some(Command::Entry(_) | Command::Exit(_)) if tunnel.token.is_none() => {
+            bail!("`tunnel.token` must be set, or passed as --token");
+        }
+        _ => {}
+    }
+
+    for (i, chaos) in config.chaos.iter().enumerate() {
+        for (key, probability) in [("reset", chaos.reset), ("refuse", chaos.refuse)] {
+            if !(0.0..=1.0).contains(&probability) {
+                bail!("`chaos[{i}].{key}` must be between 0 and 1");
+            }
+        }
+    }
+
+    log_filter(config.log.level.as_deref()).wrap_err("invalid `log.level`")?;
+
+    Ok(())
+}
+
+fn check_config(cli: &Cli) -> color_eyre::Result<()> {
+    let Some(path) = &cli.config else {
+        bail!("check-config needs the --config file");
+    };
+
+    let mut config = Config::from_path(path)?;
+    apply_overrides(cli, &mut config);
+    validate(cli, &config).wrap_err_with(|| format!("invalid configuration in {}", source(cli)))?;
+
+    println!("{}: ok", path.display());
+
+    Ok(())
+}
+
 fn main() -> color_eyre::Result<()> {
     let cli = Cli::parse();
 
-    install_tracing();
     color_eyre::install()?;
 
-    debug!("{cli:?}");
+    if let Some(Command::CheckConfig) = &cli.command {
+        return check_config(&cli);
+    }
 
-    let config = cli.users.as_ref().map_or_else(
-        || {
-            warn!("users file path not set");
-            Ok(Config::default())
-        },
-        |path| {
-            if path.exists() {
-                Config::from_path(path)
-            } else {
-                warn!("users file doesn't exist");
-                Ok(Config::default())
-            }
-        },
-    )?;
+    let mut config = match &cli.config {
+        Some(path) if path.exists() => Config::from_path(path)?,
+        _ => Config::default(),
+    };
+    apply_overrides(&cli, &mut config);
+    validate(&cli, &config)
+        .wrap_err_with(|| format!("invalid configuration in {}", source(&cli)))?;
 
-    debug!("loaded {} users", config.users.len());
+    install_tracing(log_filter(config.log.level.as_deref())?);
 
-    Builder::new_current_thread()
+    debug!("{cli:?}");
+
+    match &cli.config {
+        None => warn!("config file path not set"),
+        Some(path) if !path.exists() => warn!("config file doesn't exist"),
+        Some(_) => {}
+    }
+
+    let runtime = Builder::new_current_thread()
         .enable_all()
         .build()
-        .expect("Failed building the Runtime")
-        .block_on(run(cli))
+        .expect("Failed building the Runtime");
+
+    if let Some(Command::Connect(args)) = &cli.command {
+        let res = runtime.block_on(pipe(args));
+        // stdin is read on a blocking thread that would otherwise keep the runtime alive.
+        runtime.shutdown_background();
+        return res;
+    }
+
+    if let Some(Command::Report(args)) = &cli.command {
+        let Some(dir) = &config.server.accounting else {
+            bail!("report needs the --accounting directory");
+        };
+        return Ok(accounting::report(dir, args.from, args.to, args.top)?);
+    }
+
+    debug!("loaded {} users", config.users.len());
+
+    if config.server.splice && !cfg!(target_os = "linux") {
+        warn!("splice relay is only available on Linux, using buffered relay");
+    }
+
+    runtime.block_on(run(cli, config))
 }
 
-async fn run(cli: Cli) -> color_eyre::Result<()> {
-    let listener = TcpListener::bind((cli.addr, cli.port)).await?;
+async fn pipe(args: &ConnectArgs) -> color_eyre::Result<()> {
+    let mut client = Client::new(&args.proxy);
+    if let (Some(user), Some(password)) = (&args.user, &args.password) {
+        client = client.with_credentials(user, password);
+    }
+
+    let stream = client.connect(&args.target).await?;
+    let (mut reader, mut writer) = stream.into_split();
+
+    let upload = async {
+        io::copy(&mut io::stdin(), &mut writer).await?;
+        writer.shutdown().await?;
 
-    let cli = Arc::new(cli);
+        // Keep reading until the peer closes its side as well.
+        future::pending::<io::Result<()>>().await
+    };
+    let download = async {
+        let mut stdout = io::stdout();
+        io::copy(&mut reader, &mut stdout).await?;
+        stdout.flush().await
+    };
+
+    tokio::select! {
+        res = upload => res?,
+        res = download => res?,
+    }
+
+    Ok(())
+}
+
+async fn run(cli: Cli, config: Config) -> color_eyre::Result<()> {
+    let mut listeners = systemd::listeners()?
+        .into_iter()
+        .map(TcpListener::from_std)
+        .collect::<io::Result<Vec<_>>>()?;
+    if listeners.is_empty() {
+        for addr in config.server.listeners() {
+            let listener = TcpListener::bind(addr)
+                .await
+                .wrap_err_with(|| format!("failed listening on {addr}"))?;
+            listeners.push(listener);
+        }
+    } else {
+        info!("listening on {} sockets passed by systemd", listeners.len());
+    }
+    if let Some(queue) = config.socket.fast_open {
+        for listener in &listeners {
+            sockopt::fast_open(listener, queue)?;
+        }
+    }
+
+    let tls = match (&cli.command, &config.ws.tls_cert, &config.ws.tls_key) {
+        (Some(Command::WsRemote(_)), Some(cert), Some(key)) => Some(ws::acceptor(cert, key)?),
+        _ => None,
+    };
+
+    let entry = match (&cli.command, &config.tunnel.exit, &config.tunnel.token) {
+        (Some(Command::Entry(_)), Some(exit), Some(token)) => {
+            Some(tunnel::Entry::new(exit.clone(), token.clone()))
+        }
+        _ => None,
+    };
+
+    let capture = match &config.server.capture {
+        Some(path) => Some(capture::Recorder::create(path)?),
+        None => None,
+    };
+
+    let accounting = match &config.server.accounting {
+        Some(dir) => Some(accounting::Accounting::open(dir)?),
+        None => None,
+    };
+
+    let state = Arc::new(State {
+        cli,
+        config,
+        auth: auth::Authenticator::new()?,
+        tls,
+        entry,
+        capture,
+        accounting,
+        notifier: systemd::Notifier::from_env()?,
+    });
     let clients = Arc::new(AtomicI32::new(0));
 
+    state.notify("READY=1\nSTATUS=0 active clients");
+    let mut watchdog = state
+        .notifier
+        .as_ref()
+        .and_then(systemd::Notifier::watchdog)
+        .map(time::interval);
+
     loop {
-        let (mut stream, addr) = listener.accept().await?;
+        let (mut stream, mut addr) = tokio::select! {
+            res = accept(&listeners) => res?,
+            _ = tick(&mut watchdog) => {
+                state.notify("WATCHDOG=1");
+                continue;
+            }
+        };
 
-        if clients.load(Ordering::SeqCst) >= cli.limit {
+        let ipv6 = stream.local_addr().is_ok_and(|local| local.is_ipv6());
+        if let Err(err) = state.config.socket.client.apply(SockRef::from(&stream), ipv6) {
+            warn!("failed setting client socket options: {err}");
+        }
+
+        if clients.load(Ordering::SeqCst) >= state.config.server.limit {
             let _ = stream.shutdown().await;
             continue;
         }
 
-        let cli = cli.clone();
+        let state = state.clone();
         let clients = clients.clone();
 
-        clients.fetch_add(1, Ordering::SeqCst);
+        state.status(clients.fetch_add(1, Ordering::SeqCst) + 1);
 
         tokio::task::spawn(async move {
-            let span = if cli.anon {
+            let mut trusted = state.config.proxy_protocol.trusted.iter();
+            if trusted.any(|cidr| cidr.contains(addr.ip())) {
+                match proxy_protocol::read_header(&mut stream).await {
+                    Ok(Some(client)) => addr = client,
+                    Ok(None) => {}
+                    Err(err) => {
+                        if !state.config.server.anon {
+                            error!(%addr, "{err}");
+                        }
+                        state.status(clients.fetch_sub(1, Ordering::SeqCst) - 1);
+                        return stream.shutdown().await;
+                    }
+                }
+            }
+
+            let span = if state.config.server.anon {
                 Span::none()
             } else {
                 error_span!(
                     "client",
                     %addr,
                     peer = field::Empty,
-                    user = field::Empty
+                    user = field::Empty,
+                    host = field::Empty
                 )
             };
 
             async {
                 info!("connected");
 
-                if let Err(err) = handle(&mut stream).await {
+                if let Err(err) = session(&mut stream, addr, &state).await {
                     error!("{err}");
                 }
 
-                clients.fetch_sub(1, Ordering::SeqCst);
+                state.status(clients.fetch_sub(1, Ordering::SeqCst) - 1);
 
                 info!("disconnected");
             }
                 .instrument(span)
                 .await;
 
-            stream.shutdown().await
+            // A zero linger is left by a reset, which closing the socket passes on.
+            match SockRef::from(&stream).linger() {
+                Ok(Some(linger)) if linger.is_zero() => Ok(()),
+                _ => stream.shutdown().await,
+            }
         });
     }
 }
 
-const AUTH_METHOD: u8 = 0x2;
-const NO_AUTH_METHOD: u8 = 0x0;
-const NO_METHOD: u8 = 0xff;
-const SOCKS_VERSION: u8 = 0x5;
-const SUCCESS_REPLY: u8 = 0x0;
+/// Accepts a client from whichever listener has one first.
+async fn accept(listeners: &[TcpListener]) -> io::Result<(TcpStream, SocketAddr)> {
+    future::poll_fn(|cx| {
+        listeners
+            .iter()
+            .find_map(|listener| match listener.poll_accept(cx) {
+                Poll::Ready(res) => Some(res),
+                Poll::Pending => None,
+            })
+            .map_or(Poll::Pending, Poll::Ready)
+    })
+    .await
+}
+
+/// Waits for the watchdog's next ping, forever if there is no watchdog.
+async fn tick(watchdog: &mut Option<Interval>) {
+    match watchdog {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+async fn session(
+    stream: &mut TcpStream,
+    addr: SocketAddr,
+    state: &Arc<State>,
+) -> error::Result<()> {
+    let (ws, tunnel) = (&state.config.ws, &state.config.tunnel);
+    match &state.cli.command {
+        Some(Command::WsLocal(_)) => {
+            let remote = ws.remote.as_deref().unwrap_or_default();
+            let mut remote = ws::connect(remote, ws.token.as_deref()).await?;
+
+            let (sent, received) = io::copy_bidirectional(stream, &mut remote).await?;
+            info!("sent {sent} bytes and received {received} bytes");
+
+            Ok(())
+        }
+        Some(Command::WsRemote(_)) => {
+            let token = ws.token.as_deref();
+
+            match &state.tls {
+                Some(tls) => {
+                    let upgrade = async { ws::accept(tls.accept(stream).await?, token).await };
+                    handle(&mut handshake(state, upgrade).await?, addr, state).await
+                }
+                None => {
+                    let upgrade = ws::accept(stream, token);
+                    handle(&mut handshake(state, upgrade).await?, addr, state).await
+                }
+            }
+        }
+        Some(Command::Exit(_)) => {
+            let token = tunnel.token.as_deref().unwrap_or_default();
+            Ok(tunnel::serve(stream, state, token).await?)
+        }
+        _ => handle(stream, addr, state).await,
+    }
+}
+
+/// Runs a step of accepting a client, the TLS or WebSocket handshake or the SOCKS negotiation,
+/// under the handshake timeout.
+async fn handshake<T, E, F>(state: &State, step: F) -> Result<T, E>
+where
+    F: future::Future<Output = Result<T, E>>,
+    E: From<io::Error>,
+{
+    match state.config.timeouts.handshake() {
+        Some(limit) => time::timeout(limit, step)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))?,
+        None => step.await,
+    }
+}
+
+async fn handle<S: Stream>(stream: &mut S, addr: SocketAddr, state: &State) -> error::Result<()> {
+    let (user, res) = handshake(state, negotiate(stream, addr, state)).await?;
+
+    send_reply(stream, &res).await?;
+
+    let (target, peer) = match res? {
+        Outcome::Connected(target, peer) => (target, peer),
+        Outcome::Resolved(target) => {
+            info!("resolved to {target}");
+            return Ok(());
+        }
+    };
+
+    // A relay cut short by a reset still moved bytes, they're accounted for before the error
+    // goes up.
+    let mut usage = Usage::default();
+    let res = match peer {
+        Peer::Direct(peer) => {
+            direct(stream, peer, addr, &target, state, user.as_deref(), &mut usage).await
+        }
+        Peer::Tunnel(channel) => channel.relay(stream, &usage.traffic).await.map_err(Into::into),
+    };
+    let (sent, received) = (usage.traffic.sent(), usage.traffic.received());
+    info!("sent {sent} bytes and received {received} bytes");
+
+    if let Some(accounting) = &state.accounting {
+        let destination = match (&target, usage.host) {
+            (Target::Domain(domain, _), _) => domain.clone(),
+            (Target::Addr(_), Some(host)) => host,
+            (Target::Addr(addr), None) => addr.ip().to_string(),
+        };
+        accounting.record(user.as_deref(), &destination, sent, received);
+    }
+
+    res
+}
+
+/// Answers the request with the outcome, a name too long for the reply gets a general failure
+/// instead of no answer at all.
+async fn send_reply<S>(stream: &mut S, res: &Result<Outcome, SocksError>) -> error::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut reply = SUCCESS_REPLY;
+    if let Err(err) = res {
+        reply = self::reply(err);
+    }
+
+    let mut buf = vec![SOCKS_VERSION, reply, 0];
+    let encoded = match res {
+        Ok(Outcome::Resolved(target)) => target.encode(&mut buf),
+        _ => {
+            buf.extend_from_slice(&[IPV4_TYPE, 0, 0, 0, 0, 0, 0]);
+            Ok(())
+        }
+    };
+    if encoded.is_err() {
+        buf = vec![SOCKS_VERSION, FAILURE_REPLY, 0, IPV4_TYPE, 0, 0, 0, 0, 0, 0];
+    }
+
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+
+    encoded
+}
+
+/// What a relayed session used, filled in as it goes.
+#[derive(Default)]
+struct Usage {
+    traffic: relay::Traffic,
+    /// Host name sniffed from the first client bytes.
+    host: Option<String>,
+}
+
+/// Runs the greeting, authentication and request, returning the user and the outcome
+/// that the reply reports.
+async fn negotiate<S: Stream>(
+    stream: &mut S,
+    addr: SocketAddr,
+    state: &State,
+) -> error::Result<(Option<String>, Result<Outcome, SocksError>)> {
+    let config = &state.config;
 
-async fn handle(stream: &mut TcpStream) -> error::Result<()> {
     let mut buf = [0u8; 2];
     stream.read_exact(&mut buf).await?;
 
@@ -144,19 +744,26 @@ async fn handle(stream: &mut TcpStream) -> error::Result<()> {
     let mut buf = vec![0u8; len];
     stream.read_exact(&mut buf).await?;
 
-    let method = *buf
-        .iter()
-        .find(|&&m| { m == NO_AUTH_METHOD || m == AUTH_METHOD })
-        .unwrap_or(&NO_METHOD);
+    let required = if config.auth_required() {
+        AUTH_METHOD
+    } else {
+        NO_AUTH_METHOD
+    };
+    let method = if buf.contains(&required) {
+        required
+    } else {
+        NO_METHOD
+    };
 
     let buf = [SOCKS_VERSION, method];
     stream.write_all(&buf).await?;
+    stream.flush().await?;
 
-    match method {
-        AUTH_METHOD => return Err(Error::MethodNotFound),
+    let user = match method {
+        AUTH_METHOD => Some(authenticate(stream, state).await?),
         NO_METHOD => return Err(Error::MethodNotFound),
-        _ => {}
-    }
+        _ => None,
+    };
 
     let mut buf = [0u8; 4];
     stream.read_exact(&mut buf).await?;
@@ -169,81 +776,367 @@ async fn handle(stream: &mut TcpStream) -> error::Result<()> {
         });
     }
 
-    let mut reply = SUCCESS_REPLY;
-    let res = socks(stream, buf).await;
-    if let Err(ref err) = res {
-        reply = match err {
-            SocksError::InvalidAddr { .. } => 0x8,
-            SocksError::InvalidCommand { .. } => 0x7,
-            _ => 0x1,
-        }
-    }
+    let res = socks(stream, buf, addr, state, user.as_deref()).await;
 
-    let buf = [SOCKS_VERSION, reply, 0, IPV4_TYPE, 0, 0, 0, 0, 0, 0];
+    Ok((user, res))
+}
 
-    stream.write_all(&buf).await?;
+async fn direct<S: Stream>(
+    stream: &mut S,
+    mut peer: TcpStream,
+    addr: SocketAddr,
+    target: &Target,
+    state: &State,
+    user: Option<&str>,
+    usage: &mut Usage,
+) -> error::Result<()> {
+    let config = &state.config;
+    let span = Span::current();
 
-    let mut peer = res?;
     if let Ok(addr) = peer.peer_addr() {
-        let span = Span::current();
         span.record("peer", field::display(addr));
     }
 
-    let (sent, received) = io::copy_bidirectional(stream, &mut peer).await?;
-    info!("sent {sent} bytes and received {received} bytes");
+    // IP-literal requests give domain rules nothing to match, the first client bytes might.
+    let mut first = Vec::new();
+    if config.sniff.enabled && target.domain().is_none() {
+        let name;
+        (first, name) = sniff::peek(stream, &peer).await?;
+
+        match name {
+            Some(name) => {
+                span.record("host", name.as_str());
+                info!("sniffed host {name}");
+
+                if config.sniff.denies(&name) {
+                    warn!("refused sniffed host {name}");
+                    return Err(SocksError::NotAllowed.into());
+                }
+                usage.host = Some(name);
+            }
+            None if !config.sniff.deny.is_empty() && !config.sniff.fail_open => {
+                warn!("refused a session without a sniffable host");
+                return Err(SocksError::NotAllowed.into());
+            }
+            None => {}
+        }
+    }
+
+    let domain = target.domain().or(usage.host.as_deref());
+    let session = capture(stream, &peer, addr, domain, state, user);
+
+    if !first.is_empty() {
+        if let Some(session) = &session {
+            session.data(relay::Direction::Upload, &first);
+        }
+        peer.write_all(&first).await?;
+        usage.traffic.add(relay::Direction::Upload, first.len() as u64);
+    }
+
+    let chaos = peer
+        .peer_addr()
+        .ok()
+        .and_then(|peer| Chaos::select(&config.chaos, domain, peer.ip()));
+    let tap = session.as_ref().map(|session| session as &dyn Tap);
+
+    let traffic = &usage.traffic;
+    match (tap, chaos) {
+        (None, None) => relay::relay(stream, &mut peer, config.server.splice, traffic).await?,
+        (tap, chaos) => relay::relay_buffered(stream, &mut peer, tap, chaos, traffic).await?,
+    }
 
     Ok(())
 }
 
-const IPV4_TYPE: u8 = 0x1;
-const IPV6_TYPE: u8 = 0x4;
-const DOMAIN_TYPE: u8 = 0x3;
-const CONNECT_COMMAND: u8 = 0x1;
+/// Starts recording the session if capture is on and its filters match.
+fn capture<S: Stream>(
+    stream: &S,
+    peer: &TcpStream,
+    addr: SocketAddr,
+    domain: Option<&str>,
+    state: &State,
+    user: Option<&str>,
+) -> Option<capture::Session> {
+    let recorder = state.capture.as_ref()?;
+    let (egress, peer) = (peer.local_addr().ok()?, peer.peer_addr().ok()?);
 
-async fn socks(stream: &mut TcpStream, buf: [u8; 4]) -> Result<TcpStream, SocksError> {
-    let cmd = buf[1];
-    if cmd != CONNECT_COMMAND {
-        return Err(SocksError::InvalidCommand {
-            expected: CONNECT_COMMAND,
-            found: cmd,
+    if !state.config.capture.matches(user, addr, domain, peer) {
+        return None;
+    }
+
+    // Behind WebSocket the client's socket isn't at hand, the listener stands in for it.
+    let proxy = stream
+        .as_tcp()
+        .and_then(|stream| stream.local_addr().ok())
+        .unwrap_or(SocketAddr::new(
+            state.config.server.addr,
+            state.config.server.port,
+        ));
+
+    Some(recorder.session(addr, proxy, egress, peer))
+}
+
+fn reply(err: &SocksError) -> u8 {
+    match err {
+        SocksError::InvalidAddr { .. } => ADDR_NOT_SUPPORTED_REPLY,
+        SocksError::InvalidCommand { .. } => COMMAND_NOT_SUPPORTED_REPLY,
+        SocksError::NotAllowed => NOT_ALLOWED_REPLY,
+        SocksError::Reply(reply) => *reply,
+        SocksError::Unresolved(..) => HOST_UNREACHABLE_REPLY,
+        SocksError::Io(err) if err.kind() == io::ErrorKind::TimedOut => TTL_EXPIRED_REPLY,
+        _ => FAILURE_REPLY,
+    }
+}
+
+async fn authenticate<S: Stream>(stream: &mut S, state: &State) -> error::Result<String> {
+    let ver = stream.read_u8().await?;
+    if ver != AUTH_VERSION {
+        return Err(Error::InvalidVersion {
+            expected: AUTH_VERSION,
+            found: ver,
         });
     }
 
-    let addr = buf[3];
-    let dest = match addr {
-        IPV4_TYPE => {
-            let mut octets = [0u8; 4];
-            stream.read_exact(&mut octets).await?;
+    let len = stream.read_u8().await? as usize;
+    let mut user = vec![0u8; len];
+    stream.read_exact(&mut user).await?;
 
-            let port = stream.read_u16().await?;
-            vec![SocketAddr::new(IpAddr::from(octets), port)]
-        }
-        DOMAIN_TYPE => {
-            let len = stream.read_u8().await? as usize;
-            let mut buf = vec![0u8; len];
-            stream.read_exact(&mut buf).await?;
+    let len = stream.read_u8().await? as usize;
+    let mut password = vec![0u8; len];
+    stream.read_exact(&mut password).await?;
 
-            let domain = String::from_utf8(buf)?;
-            let port = stream.read_u16().await?;
+    let user = String::from_utf8_lossy(&user).into_owned();
+    let password = String::from_utf8_lossy(&password);
+    let verified = state.auth.verify(&state.config, &user, &password).await;
 
-            net::lookup_host(format!("{domain}:{port}"))
-                .await?
-                .collect()
-        }
-        IPV6_TYPE => {
-            let mut octets = [0u8; 16];
-            stream.read_exact(&mut octets).await?;
+    let span = Span::current();
+    span.record("user", &user);
+
+    // A backend that is down says nothing about the credentials, so the client gets no
+    // verdict and sees the connection close instead of being told its password is wrong.
+    let status = match verified {
+        Ok(true) => AUTH_SUCCESS,
+        Ok(false) => AUTH_FAILURE,
+        Err(err) => return Err(Error::AuthUnavailable(err)),
+    };
+    stream.write_all(&[AUTH_VERSION, status]).await?;
+    stream.flush().await?;
 
-            let port = stream.read_u16().await?;
-            vec![SocketAddr::new(IpAddr::from(octets), port)]
+    match status {
+        AUTH_SUCCESS => Ok(user),
+        _ => Err(Error::AuthFailed),
+    }
+}
+
+async fn socks<S: Stream>(
+    stream: &mut S,
+    buf: [u8; 4],
+    addr: SocketAddr,
+    state: &State,
+    user: Option<&str>,
+) -> Result<Outcome, SocksError> {
+    let cmd = buf[1];
+    let unsupported = SocksError::InvalidCommand {
+        expected: CONNECT_COMMAND,
+        found: cmd,
+    };
+    if !matches!(cmd, CONNECT_COMMAND | RESOLVE_COMMAND | RESOLVE_PTR_COMMAND) {
+        return Err(unsupported);
+    }
+
+    let mut target = Target::read_addr(stream, buf[3]).await?;
+    if let Some(rewritten) = rewrite::rewrite(&state.config.rewrite, &target) {
+        info!("rewrote {target} to {rewritten}");
+        target = rewritten;
+    }
+
+    match (cmd, &state.entry) {
+        // Names would resolve here rather than where the traffic leaves.
+        (RESOLVE_COMMAND | RESOLVE_PTR_COMMAND, Some(_)) => Err(unsupported),
+        (RESOLVE_COMMAND, None) => Ok(Outcome::Resolved(
+            resolve::forward(&target, &state.config).await?,
+        )),
+        (RESOLVE_PTR_COMMAND, None) => Ok(Outcome::Resolved(
+            resolve::reverse(&target, &state.config).await?,
+        )),
+        (_, Some(entry)) => {
+            let (channel, peer) = entry.open(&target, addr, user).await?;
+            Span::current().record("peer", field::display(peer));
+
+            Ok(Outcome::Connected(target, Peer::Tunnel(channel)))
         }
-        _ => {
-            return Err(SocksError::InvalidAddr {
-                expected: vec![IPV4_TYPE, DOMAIN_TYPE, IPV6_TYPE],
-                found: addr,
-            })
+        (_, None) => {
+            let peer = connect(&target, addr, &state.config, user).await?;
+            Ok(Outcome::Connected(target, Peer::Direct(peer)))
         }
+    }
+}
+
+/// Resolves and connects to `target` under the configured policies.
+async fn connect(
+    target: &Target,
+    addr: SocketAddr,
+    config: &Config,
+    user: Option<&str>,
+) -> Result<TcpStream, SocksError> {
+    let (domain, dest) = match target.clone() {
+        Target::Addr(addr) => (None, vec![addr]),
+        Target::Domain(domain, port) => {
+            let dest = net::lookup_host((domain.as_str(), port)).await?.collect();
+            (Some(domain), dest)
+        }
+    };
+    let dest = config.ssrf.filter(domain.as_deref(), dest)?;
+
+    let chaos = dest
+        .first()
+        .and_then(|first| Chaos::select(&config.chaos, domain.as_deref(), first.ip()));
+    if let Some(chaos) = chaos {
+        chaos.connect().await?;
+    }
+
+    let options = &config.socket.outbound;
+    let connecting = egress::connect(&config.egress, options, user, domain.as_deref(), &dest);
+    let mut peer = match config.timeouts.connect() {
+        Some(limit) => time::timeout(limit, connecting)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))??,
+        None => connecting.await?,
     };
 
-    Ok(TcpStream::connect(&dest[..]).await?)
-}
\ No newline at end of file
+    let peer_addr = peer.peer_addr()?;
+    let upstream = config.proxy_protocol.upstream.iter().find(|upstream| {
+        let mut dest = upstream.dest.iter();
+        dest.any(|dest| dest.matches(domain.as_deref(), Some(peer_addr.ip())))
+    });
+    if let Some(upstream) = upstream {
+        let header = proxy_protocol::encode(upstream.version, addr, peer_addr);
+        peer.write_all(&header).await?;
+    }
+
+    Ok(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(config: Config) -> Arc<State> {
+        Arc::new(State {
+            cli: Cli::parse_from(["koblas"]),
+            config,
+            auth: auth::Authenticator::new().unwrap(),
+            tls: None,
+            entry: None,
+            capture: None,
+            accounting: None,
+            notifier: None,
+        })
+    }
+
+    /// Serves SOCKS clients on a loopback port, returning its address.
+    async fn serve(config: Config) -> String {
+        let state = state(config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, addr) = listener.accept().await.unwrap();
+                let state = state.clone();
+                tokio::spawn(async move { handle(&mut stream, addr, &state).await });
+            }
+        });
+        addr.to_string()
+    }
+
+    /// Echoes back what one connection sends, returning its address.
+    async fn echo() -> Target {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            io::copy(&mut reader, &mut writer).await.unwrap();
+        });
+        Target::Addr(addr)
+    }
+
+    /// Loopback destinations are only reachable with the SSRF checks off.
+    fn loopback() -> Config {
+        let mut config = Config::default();
+        config.ssrf.enabled = false;
+        config
+    }
+
+    async fn ping(client: &Client) -> error::Result<()> {
+        let mut stream = client.connect(&echo().await).await?;
+        stream.write_all(b"ping").await?;
+
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connects_without_auth() {
+        let proxy = serve(loopback()).await;
+
+        ping(&Client::new(proxy)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn authenticates_users() {
+        let mut config = loopback();
+        config.users.insert("alice".into(), "secret".into());
+        let proxy = serve(config).await;
+
+        ping(&Client::new(&proxy).with_credentials("alice", "secret"))
+            .await
+            .unwrap();
+
+        let res = ping(&Client::new(&proxy).with_credentials("alice", "guess")).await;
+        assert!(matches!(res, Err(Error::AuthFailed)), "{res:?}");
+        let res = ping(&Client::new(&proxy).with_credentials("mallory", "secret")).await;
+        assert!(matches!(res, Err(Error::AuthFailed)), "{res:?}");
+        let res = ping(&Client::new(&proxy)).await;
+        assert!(matches!(res, Err(Error::MethodNotFound)), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn reports_error_replies() {
+        let proxy = serve(Config::default()).await;
+        let client = Client::new(proxy);
+
+        // The SSRF checks refuse loopback destinations by default.
+        let res = ping(&client).await;
+        assert!(matches!(res, Err(Error::Reply(NOT_ALLOWED_REPLY))), "{res:?}");
+
+        let res = client.bind(&echo().await).await;
+        assert!(
+            matches!(res, Err(Error::Reply(COMMAND_NOT_SUPPORTED_REPLY))),
+            "{:?}",
+            res.err()
+        );
+    }
+
+    #[tokio::test]
+    async fn too_long_names_get_a_failure_reply() {
+        let mut buf = Vec::new();
+        let fits = Outcome::Resolved(Target::Domain("a".repeat(255), 80));
+        send_reply(&mut buf, &Ok(fits)).await.unwrap();
+        assert_eq!(buf[..5], [SOCKS_VERSION, SUCCESS_REPLY, 0, DOMAIN_TYPE, 255]);
+        assert_eq!(buf.len(), 5 + 255 + 2);
+
+        let mut buf = Vec::new();
+        let too_long = Outcome::Resolved(Target::Domain("a".repeat(256), 80));
+        let res = send_reply(&mut buf, &Ok(too_long)).await;
+        assert!(matches!(res, Err(Error::InvalidTarget(_))), "{res:?}");
+        assert_eq!(
+            buf,
+            [SOCKS_VERSION, FAILURE_REPLY, 0, IPV4_TYPE, 0, 0, 0, 0, 0, 0]
+        );
+    }
+}
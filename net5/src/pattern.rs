@@ -0,0 +1,267 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::{fmt, net};
+
+/// `addr/prefix` network, a bare address is a single host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match (self.addr, ip) {
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => IpAddr::V4(ip),
+                None => return false,
+            },
+            _ => ip,
+        };
+
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |err: &dyn Display| format!("invalid network `{s}`: {err}");
+
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|err: net::AddrParseError| invalid(&err))?;
+
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid(&"bad prefix"))?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid(&"prefix out of range"));
+        }
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Destination matcher used by config rules: a network or a domain glob.
+///
+/// Globs match case-insensitively and `*` spans any number of characters,
+/// so `*.example.com` matches every subdomain but not `example.com` itself.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Pattern {
+    Network(Cidr),
+    Domain(String),
+}
+
+impl Pattern {
+    /// The domain is normalised like the pattern, so `Example.com.` is `example.com`.
+    pub fn matches_domain(&self, domain: &str) -> bool {
+        match self {
+            Self::Domain(glob) => {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                glob_matches(glob.as_bytes(), domain.as_bytes())
+            }
+            Self::Network(_) => false,
+        }
+    }
+
+    pub fn matches_ip(&self, ip: IpAddr) -> bool {
+        match self {
+            Self::Network(cidr) => cidr.contains(ip),
+            Self::Domain(_) => false,
+        }
+    }
+
+    pub fn matches(&self, domain: Option<&str>, ip: Option<IpAddr>) -> bool {
+        domain.is_some_and(|domain| self.matches_domain(domain))
+            || ip.is_some_and(|ip| self.matches_ip(ip))
+    }
+}
+
+fn glob_matches(glob: &[u8], s: &[u8]) -> bool {
+    match glob.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|i| glob_matches(rest, &s[i..])),
+        Some((c, rest)) => s.first() == Some(c) && glob_matches(rest, &s[1..]),
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty pattern".to_owned());
+        }
+
+        match s.parse::<Cidr>() {
+            Ok(cidr) => Ok(Self::Network(cidr)),
+            Err(_) if s.contains('/') => Err(format!("invalid network `{s}`")),
+            Err(_) => Ok(Self::Domain(s.trim_end_matches('.').to_ascii_lowercase())),
+        }
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(pattern: Pattern) -> Self {
+        pattern.to_string()
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Network(cidr) => cidr.fmt(f),
+            Self::Domain(glob) => f.write_str(glob),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn domains_match_regardless_of_trailing_dot_and_case() {
+        let pattern: Pattern = "Evil.com.".parse().unwrap();
+
+        assert!(pattern.matches_domain("evil.com"));
+        assert!(pattern.matches_domain("evil.com."));
+        assert!(pattern.matches_domain("EVIL.COM.."));
+        assert!(!pattern.matches_domain("evil.com.example"));
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn networks_contain_their_range() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.255.255")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.0")));
+        assert!(cidr("192.0.2.7").contains(ip("192.0.2.7")));
+        assert!(!cidr("192.0.2.7").contains(ip("192.0.2.6")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn zero_prefixes_contain_their_whole_family() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn ipv4_networks_contain_mapped_addresses() {
+        assert!(cidr("127.0.0.0/8").contains(ip("::ffff:127.0.0.1")));
+        assert!(!cidr("127.0.0.0/8").contains(ip("::1")));
+        assert!(!cidr("::ffff:0:0/96").contains(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        for s in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/x",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "/8",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "{s}");
+        }
+        assert!("10.0.0.0/33".parse::<Pattern>().is_err());
+        assert!("".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn networks_display_as_they_parse() {
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+        assert_eq!(cidr(&cidr("fd00::/8").to_string()), cidr("fd00::/8"));
+    }
+
+    #[test]
+    fn globs_match_any_run_of_characters() {
+        let pattern: Pattern = "*.example.com".parse().unwrap();
+        assert!(pattern.matches_domain("a.example.com"));
+        assert!(pattern.matches_domain("a.b.example.com"));
+        assert!(!pattern.matches_domain("example.com"));
+        assert!(!pattern.matches_domain("badexample.com"));
+
+        let pattern: Pattern = "api*.example.*".parse().unwrap();
+        assert!(pattern.matches_domain("api.example.org"));
+        assert!(pattern.matches_domain("api-v2.example.com"));
+        assert!(!pattern.matches_domain("www.example.com"));
+
+        let pattern: Pattern = "*".parse().unwrap();
+        assert!(pattern.matches_domain(""));
+        assert!(pattern.matches_domain("anything"));
+    }
+
+    #[test]
+    fn kinds_only_match_their_own_kind() {
+        let domain: Pattern = "localhost".parse().unwrap();
+        let network: Pattern = "127.0.0.0/8".parse().unwrap();
+
+        assert!(!domain.matches_ip(ip("127.0.0.1")));
+        assert!(!network.matches_domain("127.0.0.1"));
+        assert!(network.matches(Some("localhost"), Some(ip("127.0.0.1"))));
+        assert!(domain.matches(Some("LocalHost"), None));
+        assert!(!domain.matches(None, Some(ip("127.0.0.1"))));
+    }
+}
@@ -0,0 +1,181 @@
+use crate::error::{Error, Result, SocksError};
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::{fmt, result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const SOCKS_VERSION: u8 = 0x5;
+
+pub const NO_AUTH_METHOD: u8 = 0x0;
+pub const AUTH_METHOD: u8 = 0x2;
+pub const NO_METHOD: u8 = 0xff;
+
+pub const AUTH_VERSION: u8 = 0x1;
+pub const AUTH_SUCCESS: u8 = 0x0;
+pub const AUTH_FAILURE: u8 = 0x1;
+
+pub const CONNECT_COMMAND: u8 = 0x1;
+pub const BIND_COMMAND: u8 = 0x2;
+pub const UDP_ASSOCIATE_COMMAND: u8 = 0x3;
+/// Tor extension, the address of the requested domain comes back in the reply.
+pub const RESOLVE_COMMAND: u8 = 0xf0;
+/// Tor extension, the name of the requested address comes back in the reply.
+pub const RESOLVE_PTR_COMMAND: u8 = 0xf1;
+
+pub const IPV4_TYPE: u8 = 0x1;
+pub const DOMAIN_TYPE: u8 = 0x3;
+pub const IPV6_TYPE: u8 = 0x4;
+
+pub const SUCCESS_REPLY: u8 = 0x0;
+pub const FAILURE_REPLY: u8 = 0x1;
+pub const NOT_ALLOWED_REPLY: u8 = 0x2;
+pub const NETWORK_UNREACHABLE_REPLY: u8 = 0x3;
+pub const HOST_UNREACHABLE_REPLY: u8 = 0x4;
+pub const CONNECTION_REFUSED_REPLY: u8 = 0x5;
+pub const TTL_EXPIRED_REPLY: u8 = 0x6;
+pub const COMMAND_NOT_SUPPORTED_REPLY: u8 = 0x7;
+pub const ADDR_NOT_SUPPORTED_REPLY: u8 = 0x8;
+
+pub fn reply_message(reply: u8) -> &'static str {
+    match reply {
+        SUCCESS_REPLY => "succeeded",
+        FAILURE_REPLY => "general SOCKS server failure",
+        NOT_ALLOWED_REPLY => "connection not allowed by ruleset",
+        NETWORK_UNREACHABLE_REPLY => "network unreachable",
+        HOST_UNREACHABLE_REPLY => "host unreachable",
+        CONNECTION_REFUSED_REPLY => "connection refused",
+        TTL_EXPIRED_REPLY => "TTL expired",
+        COMMAND_NOT_SUPPORTED_REPLY => "command not supported",
+        ADDR_NOT_SUPPORTED_REPLY => "address type not supported",
+        _ => "unknown reply",
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Target {
+    Addr(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Target {
+    pub fn port(&self) -> u16 {
+        match self {
+            Self::Addr(addr) => addr.port(),
+            Self::Domain(_, port) => *port,
+        }
+    }
+
+    pub fn domain(&self) -> Option<&str> {
+        match self {
+            Self::Addr(_) => None,
+            Self::Domain(domain, _) => Some(domain),
+        }
+    }
+
+    pub async fn read_from<R: AsyncRead + Unpin>(
+        reader: &mut R,
+    ) -> result::Result<Self, SocksError> {
+        let addr = reader.read_u8().await?;
+        Self::read_addr(reader, addr).await
+    }
+
+    pub async fn read_addr<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        addr: u8,
+    ) -> result::Result<Self, SocksError> {
+        let target = match addr {
+            IPV4_TYPE => {
+                let mut octets = [0u8; 4];
+                reader.read_exact(&mut octets).await?;
+
+                let port = reader.read_u16().await?;
+                Self::Addr(SocketAddr::new(IpAddr::from(octets), port))
+            }
+            DOMAIN_TYPE => {
+                let len = reader.read_u8().await? as usize;
+                let mut buf = vec![0u8; len];
+                reader.read_exact(&mut buf).await?;
+
+                let domain = String::from_utf8(buf)?;
+                let port = reader.read_u16().await?;
+                Self::Domain(domain, port)
+            }
+            IPV6_TYPE => {
+                let mut octets = [0u8; 16];
+                reader.read_exact(&mut octets).await?;
+
+                let port = reader.read_u16().await?;
+                Self::Addr(SocketAddr::new(IpAddr::from(octets), port))
+            }
+            _ => {
+                return Err(SocksError::InvalidAddr {
+                    expected: vec![IPV4_TYPE, DOMAIN_TYPE, IPV6_TYPE],
+                    found: addr,
+                })
+            }
+        };
+
+        Ok(target)
+    }
+
+    /// Appends `ATYP | ADDR | PORT` as used by requests, replies and UDP headers.
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Self::Addr(SocketAddr::V4(addr)) => {
+                buf.push(IPV4_TYPE);
+                buf.extend_from_slice(&addr.ip().octets());
+            }
+            Self::Addr(SocketAddr::V6(addr)) => {
+                buf.push(IPV6_TYPE);
+                buf.extend_from_slice(&addr.ip().octets());
+            }
+            Self::Domain(domain, _) => {
+                let len =
+                    u8::try_from(domain.len()).map_err(|_| Error::InvalidTarget(domain.clone()))?;
+
+                buf.push(DOMAIN_TYPE);
+                buf.push(len);
+                buf.extend_from_slice(domain.as_bytes());
+            }
+        }
+        buf.extend_from_slice(&self.port().to_be_bytes());
+
+        Ok(())
+    }
+}
+
+impl From<SocketAddr> for Target {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Addr(addr)
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Addr(addr) => addr.fmt(f),
+            Self::Domain(domain, port) => write!(f, "{domain}:{port}"),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(addr) = s.parse() {
+            return Ok(Self::Addr(addr));
+        }
+
+        let invalid = || Error::InvalidTarget(s.to_owned());
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse().map_err(|_| invalid())?;
+
+        if host.is_empty() || host.len() > u8::MAX as usize || host.contains(':') {
+            return Err(invalid());
+        }
+
+        Ok(Self::Domain(host.to_owned(), port))
+    }
+}
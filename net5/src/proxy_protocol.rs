@@ -0,0 +1,320 @@
+//! HAProxy PROXY protocol, see <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V2_VERSION: u8 = 0x20;
+const V2_LOCAL: u8 = 0x0;
+const V2_PROXY: u8 = 0x1;
+const V2_INET_STREAM: u8 = 0x11;
+const V2_INET6_STREAM: u8 = 0x21;
+
+/// How long a trusted peer has to deliver the header.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Version {
+    #[default]
+    V1,
+    V2,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("proxy protocol: {msg}"))
+}
+
+/// Reads a v1 or v2 header and returns the original client address.
+///
+/// `None` means the sender had no client to report (`LOCAL` or `UNKNOWN`), e.g. a health check.
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    // The whole header, a peer trickling it in byte by byte gets no longer than one sent at once.
+    let read = async {
+        let mut buf = [0u8; 12];
+        reader.read_exact(&mut buf).await?;
+
+        if buf == V2_SIGNATURE {
+            read_v2(reader).await
+        } else if buf.starts_with(V1_PREFIX) {
+            read_v1(reader, buf.to_vec()).await
+        } else {
+            Err(invalid("missing header"))
+        }
+    };
+
+    time::timeout(HEADER_TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "proxy protocol: header timed out"))?
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    mut line: Vec<u8>,
+) -> io::Result<Option<SocketAddr>> {
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("v1 header too long"));
+        }
+        line.push(reader.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[V1_PREFIX.len()..line.len() - 2])
+        .map_err(|_| invalid("v1 header is not ASCII"))?;
+    let fields: Vec<_> = line.split(' ').collect();
+
+    match fields[..] {
+        ["UNKNOWN", ..] => Ok(None),
+        ["TCP4" | "TCP6", src, _, sport, _] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad v1 source address"))?;
+            let port = sport.parse().map_err(|_| invalid("bad v1 source port"))?;
+
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed v1 header")),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    let ver_cmd = reader.read_u8().await?;
+    let family = reader.read_u8().await?;
+    let len = reader.read_u16().await? as usize;
+
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+
+    if ver_cmd & 0xf0 != V2_VERSION {
+        return Err(invalid("unsupported v2 version"));
+    }
+
+    match ver_cmd & 0x0f {
+        V2_LOCAL => return Ok(None),
+        V2_PROXY => {}
+        _ => return Err(invalid("unsupported v2 command")),
+    }
+
+    // Addresses are followed by optional TLVs, which are ignored.
+    match family {
+        V2_INET_STREAM if len >= 12 => {
+            let ip: [u8; 4] = body[0..4].try_into().unwrap();
+            let port = u16::from_be_bytes([body[8], body[9]]);
+
+            Ok(Some(SocketAddr::new(IpAddr::from(ip), port)))
+        }
+        V2_INET6_STREAM if len >= 36 => {
+            let ip: [u8; 16] = body[0..16].try_into().unwrap();
+            let port = u16::from_be_bytes([body[32], body[33]]);
+
+            Ok(Some(SocketAddr::new(IpAddr::from(ip), port)))
+        }
+        V2_INET_STREAM | V2_INET6_STREAM => Err(invalid("truncated v2 addresses")),
+        // UNIX sockets and datagrams carry nothing we could use as a client address.
+        _ => Ok(None),
+    }
+}
+
+/// Both addresses of one header must share a family, so IPv4 is mapped when they differ.
+pub fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let to_v6 = |addr: SocketAddr| match addr {
+        SocketAddr::V4(v4) => SocketAddr::new(IpAddr::from(v4.ip().to_ipv6_mapped()), v4.port()),
+        addr => addr,
+    };
+
+    if src.is_ipv4() == dst.is_ipv4() {
+        (src, dst)
+    } else {
+        (to_v6(src), to_v6(dst))
+    }
+}
+
+pub fn encode(version: Version, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (src, dst) = same_family(src, dst);
+
+    match version {
+        Version::V1 => {
+            let proto = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {proto} {} {} {} {}\r\n",
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            )
+            .into_bytes()
+        }
+        Version::V2 => {
+            let mut buf = V2_SIGNATURE.to_vec();
+            buf.push(V2_VERSION | V2_PROXY);
+
+            match (src.ip(), dst.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    buf.push(V2_INET_STREAM);
+                    buf.extend_from_slice(&12u16.to_be_bytes());
+                    buf.extend_from_slice(&src.octets());
+                    buf.extend_from_slice(&dst.octets());
+                }
+                (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                    buf.push(V2_INET6_STREAM);
+                    buf.extend_from_slice(&36u16.to_be_bytes());
+                    buf.extend_from_slice(&src.octets());
+                    buf.extend_from_slice(&dst.octets());
+                }
+                _ => unreachable!("families are unified above"),
+            }
+
+            buf.extend_from_slice(&src.port().to_be_bytes());
+            buf.extend_from_slice(&dst.port().to_be_bytes());
+            buf
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    async fn parse(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+        read_header(&mut &header[..]).await
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn round_trips_both_versions() {
+        let pairs = [
+            ("192.0.2.1:40000", "198.51.100.2:1080"),
+            ("[2001:db8::1]:40000", "[2001:db8::2]:1080"),
+        ];
+
+        for version in [Version::V1, Version::V2] {
+            for (src, dst) in pairs {
+                let header = encode(version, addr(src), addr(dst));
+                assert_eq!(
+                    parse(&header).await.unwrap(),
+                    Some(addr(src)),
+                    "{version:?}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn mixed_families_map_ipv4() {
+        let header = encode(
+            Version::V2,
+            addr("192.0.2.1:40000"),
+            addr("[2001:db8::2]:1080"),
+        );
+        let client = parse(&header).await.unwrap().unwrap();
+
+        assert_eq!(client, addr("[::ffff:192.0.2.1]:40000"));
+        let header = encode(
+            Version::V1,
+            addr("192.0.2.1:40000"),
+            addr("[2001:db8::2]:1080"),
+        );
+        assert!(header.starts_with(b"PROXY TCP6 ::ffff:192.0.2.1 "));
+    }
+
+    #[tokio::test]
+    async fn leaves_what_follows_the_header() {
+        let mut input = encode(Version::V1, addr("192.0.2.1:1"), addr("192.0.2.2:2"));
+        input.extend_from_slice(b"\x05\x01\x00");
+        let mut reader = &input[..];
+
+        read_header(&mut reader).await.unwrap();
+        assert_eq!(reader, b"\x05\x01\x00");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_a_trickled_header() {
+        let (mut peer, mut reader) = tokio::io::duplex(64);
+        let trickle = tokio::spawn(async move {
+            peer.write_all(b"PROXY TCP4 1").await.unwrap();
+            for byte in b"92.0.2.1 192.0.2.2 1 2\r\n" {
+                time::sleep(Duration::from_secs(1)).await;
+                peer.write_u8(*byte).await.unwrap();
+            }
+            peer
+        });
+
+        let res = time::timeout(HEADER_TIMEOUT * 2, read_header(&mut reader)).await;
+        assert_eq!(res.unwrap().unwrap_err().kind(), io::ErrorKind::TimedOut);
+        trickle.abort();
+    }
+
+    #[tokio::test]
+    async fn reports_no_client_for_local_and_unknown() {
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        assert_eq!(
+            parse(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n")
+                .await
+                .unwrap(),
+            None
+        );
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[V2_VERSION | V2_LOCAL, 0, 0, 0]);
+        assert_eq!(parse(&local).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_v1() {
+        let headers: [&[u8]; 6] = [
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 1\r\n",
+            b"PROXY TCP4 192.0.2.x 192.0.2.2 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 70000 2\r\n",
+            b"PROXY TCP4 \xff\xfe 192.0.2.2 1 2\r\n",
+            b"PROXY SCTP 192.0.2.1 192.0.2.2 1 2\r\n",
+        ];
+        for header in headers {
+            let err = parse(header).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{header:?}");
+        }
+
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LEN));
+        assert_eq!(
+            parse(long.as_bytes()).await.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_v2() {
+        let header = |ver_cmd: u8, family: u8, body: &[u8]| {
+            let mut buf = V2_SIGNATURE.to_vec();
+            buf.extend_from_slice(&[ver_cmd, family]);
+            buf.extend_from_slice(&(body.len() as u16).to_be_bytes());
+            buf.extend_from_slice(body);
+            buf
+        };
+
+        let wrong_version = header(0x10 | V2_PROXY, V2_INET_STREAM, &[0; 12]);
+        let wrong_command = header(V2_VERSION | 0x2, V2_INET_STREAM, &[0; 12]);
+        let truncated = header(V2_VERSION | V2_PROXY, V2_INET6_STREAM, &[0; 12]);
+        for header in [wrong_version, wrong_command, truncated] {
+            assert_eq!(
+                parse(&header).await.unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+
+        // The announced length runs past the end of the input.
+        let mut short = header(V2_VERSION | V2_PROXY, V2_INET_STREAM, &[0; 12]);
+        short.truncate(short.len() - 1);
+        assert_eq!(
+            parse(&short).await.unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
@@ -0,0 +1,448 @@
+use crate::config::Chaos;
+use socket2::SockRef;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+
+/// Client-side transport of a session.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {
+    /// The socket itself when the transport is plain TCP, which `splice(2)` needs.
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl Stream for TcpStream {
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+impl<T: Stream + ?Sized> Stream for &mut T {
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        (**self).as_tcp()
+    }
+}
+
+/// Which way relayed bytes flow, `Upload` is from the client to the peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// Bytes relayed each way as seen from the client, counted as they are written so that a
+/// session ending in a reset or an error is accounted for all the same.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Traffic {
+    pub fn add(&self, dir: Direction, n: u64) {
+        let counter = match dir {
+            Direction::Upload => &self.sent,
+            Direction::Download => &self.received,
+        };
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+}
+
+/// Relays both directions until each side has shut down, counting the bytes in `traffic`.
+///
+/// With `zero_copy` on Linux the payload moves through a kernel pipe with `splice(2)`
+/// and never enters user space. Callers that need to look at or pace the traffic
+/// must use [`relay_buffered`].
+pub async fn relay<S: Stream>(
+    client: &mut S,
+    peer: &mut TcpStream,
+    zero_copy: bool,
+    traffic: &Traffic,
+) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    if let (true, Some(client)) = (zero_copy, client.as_tcp()) {
+        return splice::copy_bidirectional(client, peer, traffic).await;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = zero_copy;
+
+    relay_buffered(client, peer, None, None, traffic).await
+}
+
+/// Observer of the buffered relay, called before each chunk is forwarded.
+pub trait Tap: Sync {
+    fn data(&self, dir: Direction, data: &[u8]);
+    /// The sending side of `dir` has shut down.
+    fn fin(&self, dir: Direction);
+}
+
+/// Chunks in flight per direction under chaos, which caps throughput at
+/// `CHAOS_QUEUE * BUF_SIZE` per `latency`.
+const CHAOS_QUEUE: usize = 64;
+const BUF_SIZE: usize = 16 * 1024;
+
+/// Buffered relay that shows every chunk to `tap` and degrades the traffic as `chaos`
+/// says, `splice(2)` is never used here.
+///
+/// A reset, injected or not, leaves both sockets lingering for zero seconds under chaos so
+/// that closing them sends RST to each side.
+pub async fn relay_buffered<S: Stream>(
+    client: &mut S,
+    peer: &mut TcpStream,
+    tap: Option<&dyn Tap>,
+    chaos: Option<&Chaos>,
+    traffic: &Traffic,
+) -> io::Result<()> {
+    let res = {
+        let (mut client_reader, mut client_writer) = io::split(&mut *client);
+        let (mut peer_reader, mut peer_writer) = peer.split();
+
+        tokio::try_join!(
+            copy_buffered(
+                &mut client_reader,
+                &mut peer_writer,
+                Direction::Upload,
+                tap,
+                chaos,
+                traffic
+            ),
+            copy_buffered(
+                &mut peer_reader,
+                &mut client_writer,
+                Direction::Download,
+                tap,
+                chaos,
+                traffic
+            ),
+        )
+        .map(|_| ())
+    };
+
+    if let (Err(err), Some(_)) = (&res, chaos) {
+        if err.kind() == io::ErrorKind::ConnectionReset {
+            let sockets = client.as_tcp().into_iter().chain([&*peer]);
+            for socket in sockets {
+                let _ = SockRef::from(socket).set_linger(Some(Duration::ZERO));
+            }
+        }
+    }
+
+    res
+}
+
+async fn copy_buffered<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    dir: Direction,
+    tap: Option<&dyn Tap>,
+    chaos: Option<&Chaos>,
+    traffic: &Traffic,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let Some(chaos) = chaos else {
+        let mut buf = vec![0u8; BUF_SIZE];
+
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                if let Some(tap) = tap {
+                    tap.fin(dir);
+                }
+                writer.shutdown().await?;
+
+                return Ok(());
+            }
+
+            if let Some(tap) = tap {
+                tap.data(dir, &buf[..n]);
+            }
+            writer.write_all(&buf[..n]).await?;
+            writer.flush().await?;
+            traffic.add(dir, n as u64);
+        }
+    };
+
+    // The reader stamps each chunk with the time it's due and the writer holds it back until
+    // then, so that latency doesn't stall reading. Under a rate limit chunks are kept to a
+    // tenth of a second's worth to avoid bursts.
+    let byte_time = chaos.byte_time();
+    let size = match chaos.rate {
+        Some(rate) if rate > 0 => (rate / 10).clamp(1, BUF_SIZE as u64) as usize,
+        _ => BUF_SIZE,
+    };
+    let (tx, mut rx) = mpsc::channel::<(Instant, Vec<u8>)>(CHAOS_QUEUE);
+
+    let read = async move {
+        let mut buf = vec![0u8; size];
+
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                if let Some(tap) = tap {
+                    tap.fin(dir);
+                }
+                return Ok(());
+            }
+
+            chaos.reset()?;
+            if let Some(tap) = tap {
+                tap.data(dir, &buf[..n]);
+            }
+
+            let due = Instant::now() + chaos.delay();
+            if tx.send((due, buf[..n].to_vec())).await.is_err() {
+                return Ok(());
+            }
+        }
+    };
+
+    let write = async {
+        let mut next = Instant::now();
+
+        while let Some((due, data)) = rx.recv().await {
+            time::sleep_until(due.max(next)).await;
+            writer.write_all(&data).await?;
+            writer.flush().await?;
+            traffic.add(dir, data.len() as u64);
+
+            if let Some(byte_time) = byte_time {
+                next = Instant::now() + byte_time * data.len() as u32;
+            }
+        }
+        writer.shutdown().await?;
+
+        io::Result::Ok(())
+    };
+
+    tokio::try_join!(read, write)?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod splice {
+    use super::{Direction, Traffic};
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use tokio::io::Interest;
+    use tokio::net::TcpStream;
+
+    const PIPE_SIZE: usize = 1 << 20;
+
+    pub async fn copy_bidirectional(
+        client: &TcpStream,
+        peer: &TcpStream,
+        traffic: &Traffic,
+    ) -> io::Result<()> {
+        tokio::try_join!(
+            copy(client, peer, Direction::Upload, traffic),
+            copy(peer, client, Direction::Download, traffic)
+        )
+        .map(|_| ())
+    }
+
+    struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+        size: usize,
+    }
+
+    impl Pipe {
+        fn new() -> io::Result<Self> {
+            let mut fds = [0; 2];
+            let flags = libc::O_NONBLOCK | libc::O_CLOEXEC;
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), flags) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let (read, write) =
+                unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+            // A bigger pipe means fewer syscalls; the default 64 KiB is kept if the limit forbids it.
+            let size = unsafe {
+                libc::fcntl(
+                    write.as_raw_fd(),
+                    libc::F_SETPIPE_SZ,
+                    PIPE_SIZE as libc::c_int,
+                )
+            };
+            let size = if size > 0 { size as usize } else { 1 << 16 };
+
+            Ok(Self { read, write, size })
+        }
+    }
+
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+        let n = unsafe {
+            libc::splice(
+                from,
+                std::ptr::null_mut(),
+                to,
+                std::ptr::null_mut(),
+                len,
+                flags,
+            )
+        };
+
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    /// Moves `from`'s inbound data to `to` until EOF, then half-closes `to`.
+    async fn copy(
+        from: &TcpStream,
+        to: &TcpStream,
+        dir: Direction,
+        traffic: &Traffic,
+    ) -> io::Result<()> {
+        let pipe = Pipe::new()?;
+
+        loop {
+            from.readable().await?;
+            let n = match from.try_io(Interest::READABLE, || {
+                splice(from.as_raw_fd(), pipe.write.as_raw_fd(), pipe.size)
+            }) {
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            };
+
+            if n == 0 {
+                break;
+            }
+
+            let mut pending = n;
+            while pending > 0 {
+                to.writable().await?;
+                match to.try_io(Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), to.as_raw_fd(), pending)
+                }) {
+                    Ok(n) => {
+                        pending -= n;
+                        traffic.add(dir, n as u64);
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        if unsafe { libc::shutdown(to.as_raw_fd(), libc::SHUT_WR) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::NotConnected {
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    fn payload(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(seed)).collect()
+    }
+
+    /// Relays a request and a response bigger than a pipe's default size, the peer only
+    /// answers once it has seen the client's half-close. Returns the counted traffic.
+    async fn exchange(zero_copy: bool) -> (u64, u64) {
+        let (mut client, mut client_side) = pair().await;
+        let (mut peer_side, mut peer) = pair().await;
+        let request = payload(300_000, 7);
+        let response = payload(200_000, 13);
+        let traffic = Traffic::default();
+
+        let client_task = async {
+            client.write_all(&request).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let peer_task = async {
+            let mut received = Vec::new();
+            peer.read_to_end(&mut received).await.unwrap();
+            peer.write_all(&response).await.unwrap();
+            peer.shutdown().await.unwrap();
+            received
+        };
+        let relay = relay(&mut client_side, &mut peer_side, zero_copy, &traffic);
+
+        let (res, received, sent) = time::timeout(Duration::from_secs(10), async {
+            tokio::join!(relay, client_task, peer_task)
+        })
+        .await
+        .expect("a half-close wasn't relayed");
+        res.unwrap();
+        assert_eq!(sent, request);
+        assert_eq!(received, response);
+
+        (traffic.sent(), traffic.received())
+    }
+
+    #[tokio::test]
+    async fn buffered_relays_and_counts_both_ways() {
+        assert_eq!(exchange(false).await, (300_000, 200_000));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn splice_relays_and_counts_like_buffered() {
+        assert_eq!(exchange(true).await, exchange(false).await);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn splice_propagates_each_half_close() {
+        let (mut client, client_side) = pair().await;
+        let (peer_side, mut peer) = pair().await;
+        let traffic = Traffic::default();
+        let relay = tokio::spawn(async move {
+            splice::copy_bidirectional(&client_side, &peer_side, &traffic).await
+        });
+
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut buf = Vec::new();
+        peer.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"ping");
+
+        // The other direction is still open after the first half-close.
+        peer.write_all(b"pong").await.unwrap();
+        peer.shutdown().await.unwrap();
+        buf.clear();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"pong");
+
+        relay.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+//! Tor's RESOLVE and RESOLVE_PTR extensions, lookups on behalf of the client.
+
+use crate::config::Config;
+use net5::error::SocksError;
+use net5::proto::*;
+use std::io;
+use std::net::IpAddr;
+use tokio::{net, task};
+use tracing::warn;
+
+/// Returns the first address of `target` that the SSRF policy lets through.
+///
+/// Names in `sniff.deny` aren't looked up, the client would learn where they are otherwise.
+pub async fn forward(target: &Target, config: &Config) -> Result<Target, SocksError> {
+    let (domain, dest) = match target {
+        Target::Addr(addr) => (None, vec![*addr]),
+        Target::Domain(domain, _) if config.sniff.denies(domain) => {
+            warn!("refused resolving {domain}");
+            return Err(SocksError::NotAllowed);
+        }
+        Target::Domain(domain, port) => {
+            let dest = net::lookup_host((domain.as_str(), *port))
+                .await
+                .map_err(|err| SocksError::Unresolved(domain.clone(), err))?
+                .collect();
+            (Some(domain.as_str()), dest)
+        }
+    };
+
+    let dest = config.ssrf.filter(domain, dest)?;
+    dest.first().map(|addr| Target::Addr(*addr)).ok_or_else(|| {
+        let err = io::Error::new(io::ErrorKind::NotFound, "no addresses");
+        SocksError::Unresolved(target.to_string(), err)
+    })
+}
+
+/// Returns the name of `target`'s address, addresses refused by the SSRF policy aren't looked up.
+pub async fn reverse(target: &Target, config: &Config) -> Result<Target, SocksError> {
+    let Target::Addr(addr) = *target else {
+        return Err(SocksError::InvalidAddr {
+            expected: vec![IPV4_TYPE, IPV6_TYPE],
+            found: DOMAIN_TYPE,
+        });
+    };
+    config.ssrf.filter(None, vec![addr])?;
+
+    let name = task::spawn_blocking(move || name_of(addr.ip()))
+        .await
+        .map_err(io::Error::from)?;
+    match name {
+        Ok(name) => answer(name, addr.port(), config),
+        Err(err) if err.kind() == io::ErrorKind::Unsupported => {
+            Err(SocksError::Reply(COMMAND_NOT_SUPPORTED_REPLY))
+        }
+        Err(err) => Err(SocksError::Unresolved(addr.ip().to_string(), err)),
+    }
+}
+
+/// Hands `name` out unless it's in `sniff.deny`, the client mustn't learn denied names either.
+fn answer(name: String, port: u16, config: &Config) -> Result<Target, SocksError> {
+    if config.sniff.denies(&name) {
+        warn!("refused revealing {name}");
+        return Err(SocksError::NotAllowed);
+    }
+
+    Ok(Target::Domain(name, port))
+}
+
+/// Blocking `getnameinfo(3)`, neither std nor tokio do reverse lookups.
+#[cfg(target_os = "linux")]
+fn name_of(ip: IpAddr) -> io::Result<String> {
+    use std::ffi::CStr;
+    use std::mem;
+
+    const MAX_HOST: usize = 1025;
+
+    let mut host = [0 as libc::c_char; MAX_HOST];
+    let res = unsafe {
+        match ip {
+            IpAddr::V4(ip) => {
+                let mut addr: libc::sockaddr_in = mem::zeroed();
+                addr.sin_family = libc::AF_INET as libc::sa_family_t;
+                addr.sin_addr.s_addr = u32::from_ne_bytes(ip.octets());
+
+                libc::getnameinfo(
+                    &addr as *const _ as *const libc::sockaddr,
+                    mem::size_of_val(&addr) as libc::socklen_t,
+                    host.as_mut_ptr(),
+                    MAX_HOST as libc::socklen_t,
+                    std::ptr::null_mut(),
+                    0,
+                    libc::NI_NAMEREQD,
+                )
+            }
+            IpAddr::V6(ip) => {
+                let mut addr: libc::sockaddr_in6 = mem::zeroed();
+                addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                addr.sin6_addr.s6_addr = ip.octets();
+
+                libc::getnameinfo(
+                    &addr as *const _ as *const libc::sockaddr,
+                    mem::size_of_val(&addr) as libc::socklen_t,
+                    host.as_mut_ptr(),
+                    MAX_HOST as libc::socklen_t,
+                    std::ptr::null_mut(),
+                    0,
+                    libc::NI_NAMEREQD,
+                )
+            }
+        }
+    };
+
+    match res {
+        0 => {
+            let name = unsafe { CStr::from_ptr(host.as_ptr()) };
+            Ok(name.to_string_lossy().into_owned())
+        }
+        libc::EAI_SYSTEM => Err(io::Error::last_os_error()),
+        code => {
+            let msg = unsafe { CStr::from_ptr(libc::gai_strerror(code)) };
+            Err(io::Error::other(msg.to_string_lossy().into_owned()))
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn name_of(_: IpAddr) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reverse lookups are only available on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(deny: &[&str]) -> Config {
+        let mut config = Config::default();
+        config.sniff.deny = deny
+            .iter()
+            .map(|pattern| pattern.parse().unwrap())
+            .collect();
+        config
+    }
+
+    #[tokio::test]
+    async fn refuses_denied_names_before_looking_them_up() {
+        let config = config(&["*.blocked.invalid"]);
+
+        for name in ["ads.blocked.invalid", "Ads.Blocked.Invalid."] {
+            let target = Target::Domain(name.to_owned(), 443);
+            let err = forward(&target, &config).await.unwrap_err();
+            assert!(matches!(err, SocksError::NotAllowed), "{name}: {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn addresses_only_go_through_the_ssrf_policy() {
+        let config = config(&["*"]);
+
+        let public = Target::Addr("192.0.2.1:80".parse().unwrap());
+        assert_eq!(forward(&public, &config).await.unwrap(), public);
+
+        let loopback = Target::Addr("127.0.0.1:80".parse().unwrap());
+        let err = forward(&loopback, &config).await.unwrap_err();
+        assert!(matches!(err, SocksError::NotAllowed), "{err}");
+    }
+
+    #[test]
+    fn refuses_revealing_denied_names() {
+        let config = config(&["*.blocked.invalid"]);
+
+        let err = answer("ads.blocked.invalid".to_owned(), 0, &config).unwrap_err();
+        assert!(matches!(err, SocksError::NotAllowed), "{err}");
+
+        let name = "host.example.invalid".to_owned();
+        assert_eq!(
+            answer(name.clone(), 0, &config).unwrap(),
+            Target::Domain(name, 0)
+        );
+    }
+}
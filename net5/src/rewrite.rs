@@ -0,0 +1,242 @@
+use crate::config::Rewrite;
+use net5::proto::Target;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Replacement for a rewritten target, `host`, `host:port` or `:port`.
+///
+/// An unset part keeps the requested one, IPv6 addresses with a port go in brackets.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Destination {
+    host: Option<String>,
+    port: Option<u16>,
+}
+
+impl Destination {
+    fn apply(&self, target: &Target) -> Target {
+        let port = self.port.unwrap_or(target.port());
+
+        match &self.host {
+            Some(host) => match host.parse::<IpAddr>() {
+                Ok(ip) => Target::Addr(SocketAddr::new(ip, port)),
+                Err(_) => Target::Domain(host.clone(), port),
+            },
+            None => match target {
+                Target::Addr(addr) => Target::Addr(SocketAddr::new(addr.ip(), port)),
+                Target::Domain(domain, _) => Target::Domain(domain.clone(), port),
+            },
+        }
+    }
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| format!("invalid destination `{s}`: {msg}");
+
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']').ok_or_else(|| invalid("missing `]`"))?;
+            match rest {
+                "" => (host, None),
+                _ => match rest.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => return Err(invalid("expected `:` after `]`")),
+                },
+            }
+        } else if s.parse::<IpAddr>().is_ok() {
+            (s, None)
+        } else {
+            match s.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (s, None),
+            }
+        };
+
+        let port = port
+            .map(|port| port.parse().map_err(|_| invalid("bad port")))
+            .transpose()?;
+        let host = match host {
+            "" => None,
+            host => Some(host.trim_end_matches('.').to_ascii_lowercase()),
+        };
+        if host.is_none() && port.is_none() {
+            return Err(invalid("empty"));
+        }
+
+        Ok(Self { host, port })
+    }
+}
+
+impl TryFrom<String> for Destination {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Destination> for String {
+    fn from(dest: Destination) -> Self {
+        dest.to_string()
+    }
+}
+
+impl Display for Destination {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match (&self.host, self.port) {
+            (Some(host), Some(port)) if host.contains(':') => write!(f, "[{host}]:{port}"),
+            (Some(host), Some(port)) => write!(f, "{host}:{port}"),
+            (Some(host), None) => f.write_str(host),
+            (None, Some(port)) => write!(f, ":{port}"),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// Applies the first rule matching `target`, `None` leaves it as requested.
+pub fn rewrite(rules: &[Rewrite], target: &Target) -> Option<Target> {
+    let (domain, ip) = match target {
+        Target::Addr(addr) => (None, Some(addr.ip())),
+        Target::Domain(domain, _) => (Some(domain.as_str()), None),
+    };
+
+    rules
+        .iter()
+        .find(|rule| {
+            rule.port.is_none_or(|port| port == target.port())
+                && rule.dest.iter().any(|dest| dest.matches(domain, ip))
+        })
+        .map(|rule| rule.to.apply(target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dest(s: &str) -> Destination {
+        s.parse().unwrap()
+    }
+
+    fn rule(dest: &[&str], port: Option<u16>, to: &str) -> Rewrite {
+        Rewrite {
+            dest: dest
+                .iter()
+                .map(|pattern| pattern.parse().unwrap())
+                .collect(),
+            port,
+            to: to.parse().unwrap(),
+        }
+    }
+
+    fn domain(name: &str, port: u16) -> Target {
+        Target::Domain(name.to_owned(), port)
+    }
+
+    fn addr(s: &str) -> Target {
+        Target::Addr(s.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_every_form() {
+        let parsed = |s: &str| {
+            let dest = dest(s);
+            (dest.host, dest.port)
+        };
+
+        assert_eq!(
+            parsed("Mirror.Example."),
+            (Some("mirror.example".into()), None)
+        );
+        assert_eq!(parsed("mirror:8080"), (Some("mirror".into()), Some(8080)));
+        assert_eq!(parsed(":8080"), (None, Some(8080)));
+        assert_eq!(parsed("10.0.0.1:80"), (Some("10.0.0.1".into()), Some(80)));
+        assert_eq!(parsed("2001:db8::1"), (Some("2001:db8::1".into()), None));
+        assert_eq!(parsed("[2001:db8::1]"), (Some("2001:db8::1".into()), None));
+        assert_eq!(
+            parsed("[2001:db8::1]:443"),
+            (Some("2001:db8::1".into()), Some(443))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_destinations() {
+        for s in [
+            "",
+            ":",
+            "host:",
+            "host:http",
+            "host:70000",
+            "[::1",
+            "[::1]443",
+            "[::1]:",
+        ] {
+            assert!(s.parse::<Destination>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn displays_as_it_parses() {
+        for s in [
+            "mirror",
+            "mirror:8080",
+            ":8080",
+            "10.0.0.1:80",
+            "::1",
+            "[::1]:443",
+        ] {
+            assert_eq!(dest(s).to_string(), s);
+            assert_eq!(dest(&dest(s).to_string()), dest(s));
+        }
+    }
+
+    #[test]
+    fn unset_parts_keep_the_request() {
+        assert_eq!(
+            dest(":8443").apply(&domain("a.com", 443)),
+            domain("a.com", 8443)
+        );
+        assert_eq!(dest(":8443").apply(&addr("[::1]:443")), addr("[::1]:8443"));
+        assert_eq!(
+            dest("b.com").apply(&addr("10.0.0.1:80")),
+            domain("b.com", 80)
+        );
+        assert_eq!(
+            dest("10.0.0.2").apply(&domain("a.com", 80)),
+            addr("10.0.0.2:80")
+        );
+        assert_eq!(
+            dest("[::2]:53").apply(&domain("a.com", 80)),
+            addr("[::2]:53")
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = [
+            rule(&["ads.example.com"], Some(80), "sinkhole:8080"),
+            rule(&["*.example.com"], None, "mirror.example.net"),
+            rule(&["ads.example.com"], None, "never"),
+            rule(&["10.0.0.0/8"], Some(22), ":2222"),
+        ];
+
+        assert_eq!(
+            rewrite(&rules, &domain("ads.example.com", 80)),
+            Some(domain("sinkhole", 8080))
+        );
+        assert_eq!(
+            rewrite(&rules, &domain("ads.example.com", 443)),
+            Some(domain("mirror.example.net", 443))
+        );
+        assert_eq!(
+            rewrite(&rules, &addr("10.1.2.3:22")),
+            Some(addr("10.1.2.3:2222"))
+        );
+        assert_eq!(rewrite(&rules, &addr("10.1.2.3:23")), None);
+        assert_eq!(rewrite(&rules, &domain("example.com", 80)), None);
+    }
+}
@@ -0,0 +1,313 @@
+//! Server name from the first client bytes: TLS ClientHello SNI or HTTP `Host`.
+
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// How long the client has to send a name before it counts as having none.
+pub const PEEK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most bytes held back from the peer while looking for a name.
+const MAX_PEEK: usize = 16 * 1024 + 5;
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_SERVER_NAME: u16 = 0x0000;
+const TLS_HOST_NAME: u8 = 0x00;
+
+const HTTP_METHODS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "DELETE", "OPTIONS", "PATCH", "TRACE", "CONNECT",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+enum Parse {
+    Name(String),
+    Incomplete,
+    Unknown,
+}
+
+/// Reads the client's first bytes and looks for a server name in them.
+///
+/// The bytes are returned so that the caller can forward them. Protocols where the server
+/// speaks first end the wait as soon as `peer` becomes readable.
+pub async fn peek<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    peer: &TcpStream,
+) -> io::Result<(Vec<u8>, Option<String>)> {
+    let mut buf = Vec::new();
+
+    let res = time::timeout(PEEK_TIMEOUT, async {
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            let n = tokio::select! {
+                n = reader.read(&mut chunk) => n?,
+                _ = peer.readable() => return Ok(None),
+            };
+            if n == 0 {
+                return Ok(None);
+            }
+            buf.extend_from_slice(&chunk[..n]);
+
+            match parse(&buf) {
+                Parse::Name(name) => return Ok(Some(name)),
+                Parse::Unknown => return Ok(None),
+                Parse::Incomplete if buf.len() >= MAX_PEEK => return Ok(None),
+                Parse::Incomplete => {}
+            }
+        }
+    })
+    .await;
+
+    match res {
+        Ok(Ok(name)) => Ok((buf, name)),
+        Ok(Err(err)) => Err(err),
+        Err(_) => Ok((buf, None)),
+    }
+}
+
+fn parse(buf: &[u8]) -> Parse {
+    match buf.first() {
+        Some(&TLS_HANDSHAKE) => parse_tls(buf),
+        Some(_) => parse_http(buf),
+        None => Parse::Incomplete,
+    }
+}
+
+/// Reads fields off the front of a buffer, `None` once it runs short.
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| usize::from(b[0]) << 16 | usize::from(b[1]) << 8 | usize::from(b[2]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()?;
+        self.take(len.into())
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.take(len.into())
+    }
+}
+
+/// Only the first record is looked at, a ClientHello practically always fits in one.
+fn parse_tls(buf: &[u8]) -> Parse {
+    let mut record = Cursor(buf);
+    let Some(header) = record.take(5) else {
+        return Parse::Incomplete;
+    };
+    if header[1] != 3 {
+        return Parse::Unknown;
+    }
+    let len = u16::from_be_bytes([header[3], header[4]]);
+    let Some(fragment) = record.take(len.into()) else {
+        return Parse::Incomplete;
+    };
+
+    client_hello_sni(Cursor(fragment)).map_or(Parse::Unknown, Parse::Name)
+}
+
+fn client_hello_sni(mut hello: Cursor) -> Option<String> {
+    if hello.u8()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+    let len = hello.u24()?;
+    let mut body = Cursor(hello.0.get(..len).unwrap_or(hello.0));
+
+    // Version and random, then session id, cipher suites and compression methods.
+    body.take(2 + 32)?;
+    body.vec8()?;
+    body.vec16()?;
+    body.vec8()?;
+
+    let mut extensions = Cursor(body.vec16()?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if kind != TLS_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = Cursor(data);
+        let mut names = Cursor(names.vec16()?);
+        while !names.0.is_empty() {
+            let kind = names.u8()?;
+            let name = names.vec16()?;
+            if kind == TLS_HOST_NAME {
+                return std::str::from_utf8(name).ok().map(str::to_lowercase);
+            }
+        }
+    }
+
+    None
+}
+
+fn parse_http(buf: &[u8]) -> Parse {
+    let method = buf.split(|b| *b == b' ').next().unwrap_or_default();
+    let known = HTTP_METHODS
+        .iter()
+        .any(|m| m.as_bytes().starts_with(method) || method == m.as_bytes());
+    if !known {
+        return Parse::Unknown;
+    }
+
+    let end = buf.windows(4).position(|w| w == b"\r\n\r\n");
+    let head = &buf[..end.unwrap_or(buf.len())];
+
+    // Without the end of the header, the last line may still be cut off.
+    let mut lines = head.split(|b| *b == b'\n').skip(1).peekable();
+    while let Some(line) = lines.next() {
+        if end.is_none() && lines.peek().is_none() {
+            break;
+        }
+
+        let Ok(line) = std::str::from_utf8(line) else {
+            continue;
+        };
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if name.eq_ignore_ascii_case("host") {
+            return Parse::Name(strip_port(value.trim()).to_lowercase());
+        }
+    }
+
+    match end {
+        Some(_) => Parse::Unknown,
+        None => Parse::Incomplete,
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+
+    match host.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        let mut buf = (data.len() as u16).to_be_bytes().to_vec();
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    fn client_hello(name: &str) -> Vec<u8> {
+        let mut names = vec![TLS_HOST_NAME];
+        names.extend_from_slice(&vec16(name.as_bytes()));
+        let mut extensions = 0xff01u16.to_be_bytes().to_vec();
+        extensions.extend_from_slice(&vec16(&[0]));
+        extensions.extend_from_slice(&TLS_SERVER_NAME.to_be_bytes());
+        extensions.extend_from_slice(&vec16(&vec16(&names)));
+
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend_from_slice(&vec16(&[0x13, 0x01]));
+        body.extend_from_slice(&[1, 0]);
+        body.extend_from_slice(&vec16(&extensions));
+
+        let mut handshake = vec![TLS_CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![TLS_HANDSHAKE, 3, 1];
+        record.extend_from_slice(&vec16(&handshake));
+        record
+    }
+
+    fn name(s: &str) -> Parse {
+        Parse::Name(s.to_owned())
+    }
+
+    #[test]
+    fn finds_the_sni() {
+        assert_eq!(parse(&client_hello("Example.COM")), name("example.com"));
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_client_hello() {
+        let hello = client_hello("example.com");
+
+        for len in 1..hello.len() {
+            assert_eq!(parse(&hello[..len]), Parse::Incomplete, "{len}");
+        }
+    }
+
+    #[test]
+    fn gives_up_on_other_tls_records() {
+        let mut hello = client_hello("example.com");
+        hello[1] = 2;
+        assert_eq!(parse(&hello), Parse::Unknown);
+
+        let mut hello = client_hello("example.com");
+        hello[5] = 0x02;
+        assert_eq!(parse(&hello), Parse::Unknown);
+
+        // Inner lengths running past the record.
+        let mut hello = client_hello("example.com");
+        let len = hello.len();
+        hello[len - 16] = 0xff;
+        assert_eq!(parse(&hello), Parse::Unknown);
+    }
+
+    #[test]
+    fn finds_the_http_host() {
+        let request = b"GET / HTTP/1.1\r\nUser-Agent: x\r\nhost:  Example.com:8080 \r\n\r\n";
+        assert_eq!(parse(request), name("example.com"));
+
+        let request = b"CONNECT [2001:db8::1]:443 HTTP/1.1\r\nHost: [2001:db8::1]:443\r\n\r\n";
+        assert_eq!(parse(request), name("2001:db8::1"));
+    }
+
+    #[test]
+    fn waits_for_a_whole_host_line() {
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+        // `Host: exam` could still grow into a longer name.
+        for len in 1..request.len() - 2 {
+            assert_eq!(parse(&request[..len]), Parse::Incomplete, "{len}");
+        }
+        assert_eq!(parse(&request[..request.len() - 2]), name("example.com"));
+    }
+
+    #[test]
+    fn gives_up_on_other_protocols_and_hostless_requests() {
+        assert_eq!(parse(b"SSH-2.0-OpenSSH_9.6\r\n"), Parse::Unknown);
+        assert_eq!(parse(b"\x05\x01\x00"), Parse::Unknown);
+        assert_eq!(
+            parse(b"GET / HTTP/1.0\r\nAccept: */*\r\n\r\n"),
+            Parse::Unknown
+        );
+        assert_eq!(parse(b""), Parse::Incomplete);
+    }
+}
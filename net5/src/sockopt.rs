@@ -0,0 +1,125 @@
+use crate::config::{Keepalive, SocketOptions};
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+impl SocketOptions {
+    /// Sets the configured options on `socket`, `ipv6` selects the traffic class over `IP_TOS`.
+    pub fn apply(&self, socket: SockRef<'_>, ipv6: bool) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay)?;
+        }
+        if let Some(keepalive) = &self.keepalive {
+            socket.set_tcp_keepalive(&keepalive.params()?)?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(tos) = self.tos {
+            match ipv6 {
+                true => set_tclass(&socket, tos)?,
+                false => socket.set_tos(tos.into())?,
+            }
+        }
+        if let Some(mark) = self.mark {
+            set_mark(&socket, mark)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Keepalive {
+    fn params(&self) -> io::Result<TcpKeepalive> {
+        let params = TcpKeepalive::new().with_time(Duration::from_secs(self.idle));
+        if self.interval.is_none() && self.count.is_none() {
+            return Ok(params);
+        }
+
+        probes(params, self.interval, self.count)
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn probes(
+    mut params: TcpKeepalive,
+    interval: Option<u64>,
+    count: Option<u32>,
+) -> io::Result<TcpKeepalive> {
+    if let Some(interval) = interval {
+        params = params.with_interval(Duration::from_secs(interval));
+    }
+    if let Some(count) = count {
+        params = params.with_retries(count);
+    }
+
+    Ok(params)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn probes(_: TcpKeepalive, _: Option<u64>, _: Option<u32>) -> io::Result<TcpKeepalive> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "keepalive interval and count are not supported on this platform",
+    ))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn set_tclass(socket: &SockRef<'_>, tos: u8) -> io::Result<()> {
+    socket.set_tclass_v6(tos.into())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn set_tclass(_: &SockRef<'_>, _: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "IPv6 traffic class is not supported on this platform",
+    ))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn set_mark(socket: &SockRef<'_>, mark: u32) -> io::Result<()> {
+    socket.set_mark(mark)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn set_mark(_: &SockRef<'_>, _: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_MARK is not supported on this platform",
+    ))
+}
+
+/// Lets clients send data in the SYN, `queue` bounds pending Fast Open requests.
+#[cfg(target_os = "linux")]
+pub fn fast_open(listener: &TcpListener, queue: u32) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let queue = queue as libc::c_int;
+    let res = unsafe {
+        libc::setsockopt(
+            listener.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            &queue as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&queue) as libc::socklen_t,
+        )
+    };
+
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn fast_open(_: &TcpListener, _: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP Fast Open is only supported on Linux",
+    ))
+}
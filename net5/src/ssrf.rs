@@ -0,0 +1,202 @@
+use crate::config::Ssrf;
+use net5::error::SocksError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tracing::warn;
+
+/// Well-known NAT64 prefix, `64:ff9b::/96`.
+const NAT64_PREFIX: [u8; 12] = [0, 0x64, 0xff, 0x9b, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// Reason a destination is refused, `None` if it's a public address.
+fn classify(ip: IpAddr, private: bool) -> Option<&'static str> {
+    match ip {
+        IpAddr::V4(ip) => classify_v4(ip, private),
+        IpAddr::V6(ip) => match embedded_v4(ip) {
+            Some(ip) => classify_v4(ip, private),
+            None => classify_v6(ip, private),
+        },
+    }
+}
+
+/// IPv4 address reached through an IPv6 one: IPv4-mapped `::ffff:a.b.c.d`, NAT64
+/// `64:ff9b::a.b.c.d` or IPv4-compatible `::a.b.c.d`.
+///
+/// `::` and `::1` are left alone, they are IPv6's own unspecified and loopback addresses.
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+    let (prefix, v4) = octets.split_at(12);
+    let v4 = Ipv4Addr::new(v4[0], v4[1], v4[2], v4[3]);
+
+    let mapped = ip.to_ipv4_mapped().is_some();
+    let compatible = prefix.iter().all(|b| *b == 0) && u32::from(v4) > 1;
+
+    (mapped || compatible || prefix == NAT64_PREFIX).then_some(v4)
+}
+
+fn classify_v4(ip: Ipv4Addr, private: bool) -> Option<&'static str> {
+    let [a, b, ..] = ip.octets();
+
+    if ip.is_loopback() {
+        Some("loopback")
+    } else if ip.is_link_local() {
+        Some("link-local")
+    } else if ip.is_multicast() || ip.is_broadcast() {
+        Some("multicast")
+    } else if a == 0 {
+        Some("unspecified")
+    } else if private && (ip.is_private() || (a == 100 && b & 0xc0 == 64)) {
+        Some("private")
+    } else {
+        None
+    }
+}
+
+fn classify_v6(ip: Ipv6Addr, private: bool) -> Option<&'static str> {
+    let first = ip.segments()[0];
+
+    if ip.is_loopback() {
+        Some("loopback")
+    } else if first & 0xffc0 == 0xfe80 {
+        Some("link-local")
+    } else if ip.is_multicast() {
+        Some("multicast")
+    } else if ip.is_unspecified() {
+        Some("unspecified")
+    } else if private && first & 0xfe00 == 0xfc00 {
+        Some("private")
+    } else {
+        None
+    }
+}
+
+impl Ssrf {
+    /// Drops resolved addresses the policy refuses, so that only checked addresses get connected to.
+    pub fn filter(
+        &self,
+        domain: Option<&str>,
+        dest: Vec<SocketAddr>,
+    ) -> Result<Vec<SocketAddr>, SocksError> {
+        if !self.enabled || dest.is_empty() {
+            return Ok(dest);
+        }
+
+        let allowed: Vec<_> = dest
+            .into_iter()
+            .filter(|addr| {
+                let ip = addr.ip();
+                let Some(reason) = classify(ip, self.private) else {
+                    return true;
+                };
+
+                let v4 = match ip {
+                    IpAddr::V6(ip) => embedded_v4(ip).map(IpAddr::V4),
+                    IpAddr::V4(_) => None,
+                };
+                if self.allow.iter().any(|allow| {
+                    allow.matches(domain, Some(ip)) || v4.is_some_and(|v4| allow.matches_ip(v4))
+                }) {
+                    return true;
+                }
+
+                warn!("refused {reason} destination {addr}");
+                false
+            })
+            .collect();
+
+        if allowed.is_empty() {
+            return Err(SocksError::NotAllowed);
+        }
+
+        Ok(allowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ssrf(private: bool, allow: &[&str]) -> Ssrf {
+        Ssrf {
+            enabled: true,
+            private,
+            allow: allow
+                .iter()
+                .map(|pattern| pattern.parse().unwrap())
+                .collect(),
+        }
+    }
+
+    fn refused(ip: &str) -> Option<&'static str> {
+        classify(ip.parse().unwrap(), true)
+    }
+
+    #[test]
+    fn classifies_ipv4() {
+        assert_eq!(refused("127.0.0.53"), Some("loopback"));
+        assert_eq!(refused("169.254.169.254"), Some("link-local"));
+        assert_eq!(refused("224.0.0.1"), Some("multicast"));
+        assert_eq!(refused("255.255.255.255"), Some("multicast"));
+        assert_eq!(refused("0.1.2.3"), Some("unspecified"));
+        assert_eq!(refused("10.1.2.3"), Some("private"));
+        assert_eq!(refused("100.64.0.1"), Some("private"));
+        assert_eq!(refused("100.128.0.1"), None);
+        assert_eq!(refused("192.0.2.1"), None);
+        assert_eq!(classify("10.1.2.3".parse().unwrap(), false), None);
+    }
+
+    #[test]
+    fn classifies_ipv6() {
+        assert_eq!(refused("::1"), Some("loopback"));
+        assert_eq!(refused("::"), Some("unspecified"));
+        assert_eq!(refused("fe80::1"), Some("link-local"));
+        assert_eq!(refused("febf::1"), Some("link-local"));
+        assert_eq!(refused("ff02::1"), Some("multicast"));
+        assert_eq!(refused("fd00::1"), Some("private"));
+        assert_eq!(refused("2001:db8::1"), None);
+    }
+
+    #[test]
+    fn unwraps_embedded_ipv4() {
+        for ip in ["::ffff:127.0.0.1", "64:ff9b::127.0.0.1", "::127.0.0.1"] {
+            assert_eq!(refused(ip), Some("loopback"), "{ip}");
+        }
+        for ip in [
+            "::ffff:169.254.169.254",
+            "64:ff9b::a9fe:a9fe",
+            "::169.254.169.254",
+        ] {
+            assert_eq!(refused(ip), Some("link-local"), "{ip}");
+        }
+        assert_eq!(refused("64:ff9b::10.0.0.1"), Some("private"));
+        assert_eq!(refused("64:ff9b::192.0.2.1"), None);
+        assert_eq!(refused("64:ff9b:1::127.0.0.1"), None);
+    }
+
+    #[test]
+    fn filters_refused_addresses_only() {
+        let dest = vec![
+            "192.0.2.1:80".parse().unwrap(),
+            "127.0.0.1:80".parse().unwrap(),
+        ];
+
+        let allowed = ssrf(false, &[]).filter(None, dest).unwrap();
+        assert_eq!(allowed, ["192.0.2.1:80".parse().unwrap()]);
+
+        let dest = vec!["[64:ff9b::7f00:1]:80".parse().unwrap()];
+        assert!(ssrf(false, &[]).filter(None, dest).is_err());
+    }
+
+    #[test]
+    fn allow_matches_domains_and_embedded_addresses() {
+        let dest = vec!["[64:ff9b::7f00:1]:80".parse().unwrap()];
+
+        assert!(ssrf(false, &["127.0.0.0/8"])
+            .filter(None, dest.clone())
+            .is_ok());
+        assert!(ssrf(false, &["*.internal"])
+            .filter(Some("db.internal"), dest.clone())
+            .is_ok());
+        assert!(ssrf(false, &["*.internal"])
+            .filter(Some("example.com"), dest)
+            .is_err());
+    }
+}
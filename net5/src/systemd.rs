@@ -0,0 +1,111 @@
+//! Socket activation and the `sd_notify(3)` protocol, implemented directly rather than through
+//! libsystemd.
+
+use std::io;
+use std::net::TcpListener;
+use std::time::Duration;
+
+/// Listening sockets passed by systemd, empty unless the service was socket activated.
+#[cfg(target_os = "linux")]
+pub fn listeners() -> io::Result<Vec<TcpListener>> {
+    use socket2::{SockRef, Type};
+    use std::os::fd::FromRawFd;
+
+    const LISTEN_FDS_START: i32 = 3;
+
+    // The variables are inherited by children, which the PID tells apart.
+    if var::<u32>("LISTEN_PID") != Some(std::process::id()) {
+        return Ok(Vec::new());
+    }
+    let count = var::<i32>("LISTEN_FDS").unwrap_or_default();
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // The descriptors are ours from here on and mustn't leak into children.
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let socket = SockRef::from(&listener);
+            if socket.r#type()? != Type::STREAM || !socket.is_listener()? {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("socket {fd} passed by systemd isn't a listening TCP socket"),
+                ));
+            }
+            listener.set_nonblocking(true)?;
+
+            Ok(listener)
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn listeners() -> io::Result<Vec<TcpListener>> {
+    Ok(Vec::new())
+}
+
+/// Sender of state changes to the service manager, present when it asked for them with
+/// `NOTIFY_SOCKET`.
+pub struct Notifier {
+    #[cfg(target_os = "linux")]
+    socket: std::os::unix::net::UnixDatagram,
+    #[cfg(target_os = "linux")]
+    addr: std::os::unix::net::SocketAddr,
+}
+
+impl Notifier {
+    #[cfg(target_os = "linux")]
+    pub fn from_env() -> io::Result<Option<Self>> {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return Ok(None);
+        };
+
+        // A leading `@` stands for the abstract namespace.
+        let addr = match path.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Some(Self { socket, addr }))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn from_env() -> io::Result<Option<Self>> {
+        Ok(None)
+    }
+
+    /// Sends `NAME=value` assignments, one per line.
+    #[cfg(target_os = "linux")]
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn notify(&self, _: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// How often to send `WATCHDOG=1`, half the timeout the service manager enforces.
+    pub fn watchdog(&self) -> Option<Duration> {
+        if var::<u32>("WATCHDOG_PID").is_some_and(|pid| pid != std::process::id()) {
+            return None;
+        }
+
+        var::<u64>("WATCHDOG_USEC")
+            .filter(|usec| *usec > 0)
+            .map(|usec| Duration::from_micros(usec) / 2)
+    }
+}
+
+fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok()?.parse().ok()
+}
@@ -0,0 +1,697 @@
+use crate::auth::constant_time_eq;
+use crate::relay::{Direction, Traffic};
+use crate::State;
+use net5::error::SocksError;
+use net5::proto::*;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time;
+use tracing::{error, error_span, field, info, Instrument, Span};
+
+const HELLO: u8 = 0x0;
+const OPEN: u8 = 0x1;
+const REPLY: u8 = 0x2;
+const DATA: u8 = 0x3;
+const WINDOW: u8 = 0x4;
+const FIN: u8 = 0x5;
+const RESET: u8 = 0x6;
+
+const CHUNK_SIZE: usize = 16 * 1024;
+const MAX_FRAME: usize = 64 * 1024;
+/// Bytes a stream may have in flight before the receiver acknowledges them.
+const WINDOW_SIZE: usize = 256 * 1024;
+/// Window each DATA frame takes on top of its payload, so that the window also bounds how
+/// many frames a stream can have queued.
+const FRAME_COST: usize = 64;
+/// Events a stream can have queued: the DATA frames its window allows, a reply, a FIN and
+/// a RESET.
+const QUEUE_SIZE: usize = WINDOW_SIZE / FRAME_COST + 3;
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `kind | id | len | payload`, with integers in network order.
+struct Frame {
+    kind: u8,
+    id: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn new(kind: u8, id: u32, payload: Vec<u8>) -> Self {
+        Self { kind, id, payload }
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Frame> {
+    let kind = reader.read_u8().await?;
+    let id = reader.read_u32().await?;
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("tunnel frame of {len} bytes exceeds {MAX_FRAME}"),
+        ));
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+
+    Ok(Frame { kind, id, payload })
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(9 + frame.payload.len());
+    buf.push(frame.kind);
+    buf.extend_from_slice(&frame.id.to_be_bytes());
+    buf.extend_from_slice(&(frame.payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&frame.payload);
+
+    writer.write_all(&buf).await
+}
+
+enum Event {
+    Reply(u8, Target),
+    Data(Vec<u8>),
+    Fin,
+    Reset,
+}
+
+struct Slot {
+    events: mpsc::Sender<Event>,
+    credit: Arc<Semaphore>,
+    /// What's left of the window this side granted, DATA beyond it resets the stream.
+    window: Arc<AtomicUsize>,
+}
+
+/// One tunnel connection shared by many streams.
+struct Mux {
+    frames: mpsc::UnboundedSender<Frame>,
+    slots: Mutex<HashMap<u32, Slot>>,
+    next_id: AtomicU32,
+    closed: AtomicBool,
+}
+
+impl Mux {
+    fn new() -> (Arc<Self>, mpsc::UnboundedReceiver<Frame>) {
+        let (frames, rx) = mpsc::unbounded_channel();
+        let mux = Self {
+            frames,
+            slots: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(1),
+            closed: AtomicBool::new(false),
+        };
+
+        (Arc::new(mux), rx)
+    }
+
+    fn send(&self, kind: u8, id: u32, payload: Vec<u8>) {
+        let _ = self.frames.send(Frame::new(kind, id, payload));
+    }
+
+    fn channel(self: &Arc<Self>, id: u32) -> Channel {
+        let (events, rx) = mpsc::channel(QUEUE_SIZE);
+        let credit = Arc::new(Semaphore::new(WINDOW_SIZE));
+        let window = Arc::new(AtomicUsize::new(WINDOW_SIZE));
+
+        let slot = Slot {
+            events,
+            credit: credit.clone(),
+            window: window.clone(),
+        };
+        // Checked under the lock `close` takes, a stream opened after it would wait forever.
+        let mut slots = self.slots.lock().unwrap();
+        if self.closed.load(Ordering::SeqCst) {
+            credit.close();
+        } else {
+            slots.insert(id, slot);
+        }
+        drop(slots);
+
+        Channel {
+            id,
+            mux: self.clone(),
+            events: rx,
+            credit,
+            window,
+        }
+    }
+
+    async fn write_loop<W: AsyncWrite + Unpin>(
+        mut writer: W,
+        mut frames: mpsc::UnboundedReceiver<Frame>,
+    ) -> io::Result<()> {
+        while let Some(frame) = frames.recv().await {
+            write_frame(&mut writer, &frame).await?;
+
+            if frames.is_empty() {
+                writer.flush().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resets every stream, whichever side of the connection failed first.
+    fn close(&self) {
+        let mut slots = self.slots.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+
+        for (_, slot) in slots.drain() {
+            slot.credit.close();
+            let _ = slot.events.try_send(Event::Reset);
+        }
+    }
+
+    /// Dispatches inbound frames until the connection drops, then resets every stream.
+    async fn read_loop<R, F>(self: &Arc<Self>, mut reader: R, on_open: F) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        F: Fn(Channel, Vec<u8>),
+    {
+        let res = loop {
+            let frame = match read_frame(&mut reader).await {
+                Ok(frame) => frame,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+                Err(err) => break Err(err),
+            };
+
+            if frame.kind == OPEN {
+                on_open(self.channel(frame.id), frame.payload);
+                continue;
+            }
+
+            let event = match frame.kind {
+                REPLY => {
+                    let Some((&reply, mut rest)) = frame.payload.split_first() else {
+                        break Err(io::ErrorKind::InvalidData.into());
+                    };
+                    let peer = Target::read_from(&mut rest).await;
+
+                    Event::Reply(reply, peer.unwrap_or_else(|_| unspecified()))
+                }
+                DATA => Event::Data(frame.payload),
+                WINDOW => {
+                    let Ok(increment) = <[u8; 4]>::try_from(&frame.payload[..]) else {
+                        break Err(io::ErrorKind::InvalidData.into());
+                    };
+
+                    let increment = u32::from_be_bytes(increment) as usize;
+                    if let Some(slot) = self.slots.lock().unwrap().get(&frame.id) {
+                        // Acknowledging more than was sent would overflow the semaphore.
+                        if slot.credit.available_permits() + increment > WINDOW_SIZE {
+                            break Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!(
+                                    "tunnel window of stream {} exceeds {WINDOW_SIZE}",
+                                    frame.id
+                                ),
+                            ));
+                        }
+                        slot.credit.add_permits(increment);
+                    }
+                    continue;
+                }
+                FIN => Event::Fin,
+                RESET => Event::Reset,
+                _ => continue,
+            };
+
+            let mut slots = self.slots.lock().unwrap();
+            let Some(slot) = slots.get(&frame.id) else {
+                continue;
+            };
+
+            let granted = match &event {
+                Event::Data(data) => slot
+                    .window
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |window| {
+                        window.checked_sub(data.len() + FRAME_COST)
+                    })
+                    .is_ok(),
+                _ => true,
+            };
+            // A peer ignoring the window loses the stream rather than queuing without bound.
+            if !granted || slot.events.try_send(event).is_err() {
+                // The queue may be full, dropping the sender resets the stream once it's drained.
+                if let Some(slot) = slots.remove(&frame.id) {
+                    slot.credit.close();
+                }
+                self.send(RESET, frame.id, Vec::new());
+                error!("tunnel stream {} overran its window", frame.id);
+            }
+        };
+
+        self.close();
+        res
+    }
+}
+
+/// Closes the mux when dropped, so streams are reset however the connection ends.
+struct CloseOnDrop<'a>(&'a Mux);
+
+impl Drop for CloseOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+fn unspecified() -> Target {
+    Target::Addr(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+}
+
+/// One multiplexed stream, it unregisters itself when dropped.
+pub struct Channel {
+    id: u32,
+    mux: Arc<Mux>,
+    events: mpsc::Receiver<Event>,
+    credit: Arc<Semaphore>,
+    window: Arc<AtomicUsize>,
+}
+
+impl Channel {
+    /// Pumps both directions between `stream` and the remote end, counting the bytes in
+    /// `traffic` with uploads going from `stream` to the remote end.
+    pub async fn relay<S>(mut self, stream: &mut S, traffic: &Traffic) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (id, mux, credit, window) = (self.id, &self.mux, &self.credit, &self.window);
+        let events = &mut self.events;
+
+        let upload = async {
+            let mut buf = vec![0u8; CHUNK_SIZE];
+
+            loop {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    mux.send(FIN, id, Vec::new());
+                    return Ok(());
+                }
+
+                // Waits for the receiver to acknowledge earlier data.
+                credit
+                    .acquire_many((n + FRAME_COST) as u32)
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset))?
+                    .forget();
+
+                mux.send(DATA, id, buf[..n].to_vec());
+                traffic.add(Direction::Upload, n as u64);
+            }
+        };
+
+        let download = async {
+            loop {
+                match events.recv().await {
+                    Some(Event::Data(data)) => {
+                        writer.write_all(&data).await?;
+                        writer.flush().await?;
+
+                        let cost = data.len() + FRAME_COST;
+                        window.fetch_add(cost, Ordering::SeqCst);
+                        mux.send(WINDOW, id, (cost as u32).to_be_bytes().to_vec());
+                        traffic.add(Direction::Download, data.len() as u64);
+                    }
+                    Some(Event::Fin) => {
+                        writer.shutdown().await?;
+                        return Ok(());
+                    }
+                    Some(Event::Reply(..)) => {}
+                    Some(Event::Reset) | None => {
+                        return Err(io::Error::from(io::ErrorKind::ConnectionReset))
+                    }
+                }
+            }
+        };
+
+        let res = tokio::try_join!(upload, download).map(|_| ());
+        if res.is_err() {
+            self.mux.send(RESET, id, Vec::new());
+        }
+
+        res
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.mux.slots.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Entry side: keeps one authenticated connection to the exit and reconnects when it drops.
+pub struct Entry {
+    exit: String,
+    token: String,
+    mux: tokio::sync::Mutex<Option<Arc<Mux>>>,
+}
+
+impl Entry {
+    pub fn new(exit: String, token: String) -> Self {
+        Self {
+            exit,
+            token,
+            mux: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    async fn mux(&self) -> io::Result<Arc<Mux>> {
+        let mut mux = self.mux.lock().await;
+        if let Some(mux) = mux
+            .as_ref()
+            .filter(|mux| !mux.closed.load(Ordering::SeqCst))
+        {
+            return Ok(mux.clone());
+        }
+
+        let mut stream = TcpStream::connect(self.exit.as_str()).await?;
+        stream.set_nodelay(true)?;
+
+        let hello = Frame::new(HELLO, 0, self.token.as_bytes().to_vec());
+        write_frame(&mut stream, &hello).await?;
+
+        let reply = time::timeout(HELLO_TIMEOUT, read_frame(&mut stream))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        if reply.kind != HELLO {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "tunnel handshake refused",
+            ));
+        }
+
+        info!("tunnel to {} established", self.exit);
+
+        let (new, frames) = Mux::new();
+        let (reader, writer) = stream.into_split();
+
+        tokio::spawn({
+            let mux = new.clone();
+            let exit = self.exit.clone();
+
+            async move {
+                let _close = CloseOnDrop(&mux);
+                if let Err(err) = Mux::write_loop(writer, frames).await {
+                    error!("tunnel to {exit}: {err}");
+                }
+            }
+            .instrument(Span::none())
+        });
+        tokio::spawn({
+            let mux = new.clone();
+            let exit = self.exit.clone();
+
+            async move {
+                if let Err(err) = mux.read_loop(reader, |_, _| {}).await {
+                    error!("tunnel to {exit}: {err}");
+                }
+                info!("tunnel to {exit} closed");
+            }
+            .instrument(Span::none())
+        });
+
+        *mux = Some(new.clone());
+        Ok(new)
+    }
+
+    /// Asks the exit to connect to `target`, returning the stream and the exit's peer address.
+    pub async fn open(
+        &self,
+        target: &Target,
+        client: SocketAddr,
+        user: Option<&str>,
+    ) -> Result<(Channel, Target), SocksError> {
+        let mux = self.mux().await?;
+        let id = mux.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut channel = mux.channel(id);
+
+        let user = user.unwrap_or_default().as_bytes();
+        let mut payload = vec![user.len().min(u8::MAX as usize) as u8];
+        payload.extend_from_slice(&user[..payload[0] as usize]);
+        Target::from(client)
+            .encode(&mut payload)
+            .map_err(io::Error::other)?;
+        target.encode(&mut payload).map_err(io::Error::other)?;
+        mux.send(OPEN, id, payload);
+
+        match channel.events.recv().await {
+            Some(Event::Reply(SUCCESS_REPLY, peer)) => Ok((channel, peer)),
+            Some(Event::Reply(reply, _)) => Err(SocksError::Reply(reply)),
+            _ => Err(io::Error::from(io::ErrorKind::ConnectionReset).into()),
+        }
+    }
+}
+
+/// Exit side: serves one entry connection until it drops.
+pub async fn serve(stream: &mut TcpStream, state: &Arc<State>, token: &str) -> io::Result<()> {
+    let hello = time::timeout(HELLO_TIMEOUT, read_frame(stream))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    if hello.kind != HELLO || !constant_time_eq(&hello.payload, token.as_bytes()) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "tunnel token mismatch",
+        ));
+    }
+
+    write_frame(stream, &Frame::new(HELLO, 0, Vec::new())).await?;
+    stream.set_nodelay(true)?;
+
+    let (mux, frames) = Mux::new();
+    let (reader, writer) = stream.split();
+
+    let on_open = |channel: Channel, payload: Vec<u8>| {
+        let state = state.clone();
+        let mux = mux.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = open(&state, &mux, channel, &payload).await {
+                error!("{err}");
+            }
+        });
+    };
+
+    // Either loop may end first, the streams it leaves behind must not wait forever.
+    let _close = CloseOnDrop(&mux);
+    tokio::select! {
+        res = mux.read_loop(reader, on_open) => res,
+        res = Mux::write_loop(writer, frames) => res,
+    }
+}
+
+async fn open(state: &State, mux: &Mux, channel: Channel, payload: &[u8]) -> io::Result<()> {
+    let mut payload = payload;
+    let len = payload.read_u8().await? as usize;
+    if payload.len() < len {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let (user, mut payload) = payload.split_at(len);
+    let user = (!user.is_empty()).then(|| String::from_utf8_lossy(user).into_owned());
+
+    let Target::Addr(client) = Target::read_from(&mut payload)
+        .await
+        .map_err(io::Error::other)?
+    else {
+        return Err(io::ErrorKind::InvalidData.into());
+    };
+    let target = Target::read_from(&mut payload)
+        .await
+        .map_err(io::Error::other)?;
+
+    let span = if state.config.server.anon {
+        Span::none()
+    } else {
+        error_span!(
+            "stream",
+            id = channel.id,
+            %client,
+            peer = field::Empty,
+            user = user.as_deref()
+        )
+    };
+
+    async {
+        let mut peer = match crate::connect(&target, client, &state.config, user.as_deref()).await {
+            Ok(peer) => peer,
+            Err(err) => {
+                mux.send(
+                    REPLY,
+                    channel.id,
+                    reply_payload(crate::reply(&err), unspecified()),
+                );
+                error!("{err}");
+                return Ok(());
+            }
+        };
+
+        let peer_addr = peer.peer_addr()?;
+        mux.send(
+            REPLY,
+            channel.id,
+            reply_payload(SUCCESS_REPLY, peer_addr.into()),
+        );
+        Span::current().record("peer", field::display(peer_addr));
+
+        // Uploads here come from the destination, which is the client's download.
+        let traffic = Traffic::default();
+        let res = channel.relay(&mut peer, &traffic).await;
+        info!(
+            "sent {} bytes and received {} bytes",
+            traffic.received(),
+            traffic.sent()
+        );
+
+        res
+    }
+    .instrument(span)
+    .await
+}
+
+fn reply_payload(reply: u8, peer: Target) -> Vec<u8> {
+    let mut payload = vec![reply];
+    let _ = peer.encode(&mut payload);
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![kind];
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[tokio::test]
+    async fn rejects_windows_beyond_what_was_sent() {
+        let (mux, _frames) = Mux::new();
+        let mut channel = mux.channel(1);
+
+        let input = frame(WINDOW, 1, &u32::MAX.to_be_bytes());
+        let res = mux.read_loop(&input[..], |_, _| {}).await;
+
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(channel.events.recv().await, Some(Event::Reset)));
+        assert!(channel.credit.is_closed());
+    }
+
+    #[tokio::test]
+    async fn accepts_acknowledged_windows() {
+        let (mux, _frames) = Mux::new();
+        let channel = mux.channel(1);
+        channel.credit.acquire_many(1000).await.unwrap().forget();
+
+        let input = frame(WINDOW, 1, &1000u32.to_be_bytes());
+        mux.read_loop(&input[..], |_, _| {}).await.unwrap();
+
+        // The connection ended right after, which closes the stream.
+        assert!(channel.credit.is_closed());
+        assert!(mux.slots.lock().unwrap().is_empty());
+    }
+
+    fn data(id: u32, len: usize, count: usize) -> Vec<u8> {
+        (0..count)
+            .flat_map(|_| frame(DATA, id, &vec![b'x'; len]))
+            .collect()
+    }
+
+    fn resets(frames: &mut mpsc::UnboundedReceiver<Frame>) -> usize {
+        let mut resets = 0;
+        while let Ok(frame) = frames.try_recv() {
+            resets += usize::from(frame.kind == RESET);
+        }
+        resets
+    }
+
+    #[tokio::test]
+    async fn resets_streams_overrunning_the_window() {
+        let (mux, mut frames) = Mux::new();
+        let mut channel = mux.channel(1);
+
+        // Four full frames are the window plus four times the frame cost.
+        let input = data(1, MAX_FRAME, 4);
+        mux.read_loop(&input[..], |_, _| {}).await.unwrap();
+
+        assert_eq!(resets(&mut frames), 1);
+        for _ in 0..3 {
+            assert!(matches!(channel.events.recv().await, Some(Event::Data(_))));
+        }
+        assert!(channel.events.recv().await.is_none());
+        assert!(channel.credit.is_closed());
+    }
+
+    #[tokio::test]
+    async fn charges_empty_frames_against_the_window() {
+        let (mux, mut frames) = Mux::new();
+        let mut channel = mux.channel(1);
+        let other = mux.channel(2);
+
+        let mut input = data(1, 0, WINDOW_SIZE / FRAME_COST + 1);
+        input.extend(data(2, 0, WINDOW_SIZE / FRAME_COST));
+        mux.read_loop(&input[..], |_, _| {}).await.unwrap();
+
+        assert_eq!(resets(&mut frames), 1);
+        let mut queued = 0;
+        while let Some(Event::Data(_)) = channel.events.recv().await {
+            queued += 1;
+        }
+        assert_eq!(queued, WINDOW_SIZE / FRAME_COST);
+        assert_eq!(other.window.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn acknowledged_data_reopens_the_window() {
+        let (mux, mut frames) = Mux::new();
+        let channel = mux.channel(1);
+        let (mut input, reader) = tokio::io::duplex(MAX_FRAME);
+        let (mut client, mut stream) = tokio::io::duplex(MAX_FRAME);
+
+        let read_loop = tokio::spawn({
+            let mux = mux.clone();
+            async move { mux.read_loop(reader, |_, _| {}).await }
+        });
+        tokio::spawn(async move { channel.relay(&mut stream, &Traffic::default()).await });
+
+        let mut buf = vec![0u8; MAX_FRAME];
+        for _ in 0..8 {
+            input.write_all(&data(1, MAX_FRAME, 1)).await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+        }
+
+        // Twice the window went through, all of it acknowledged and none of it reset.
+        let mut acknowledged = 0;
+        while acknowledged < 8 * (MAX_FRAME + FRAME_COST) {
+            let frame = frames.recv().await.unwrap();
+            assert_ne!(frame.kind, RESET);
+            if frame.kind == WINDOW {
+                acknowledged += u32::from_be_bytes(frame.payload[..].try_into().unwrap()) as usize;
+            }
+        }
+
+        drop(input);
+        read_loop.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn closing_resets_streams_and_refuses_new_ones() {
+        let (mux, _frames) = Mux::new();
+        let mut open = mux.channel(1);
+
+        drop(CloseOnDrop(&mux));
+        assert!(matches!(open.events.recv().await, Some(Event::Reset)));
+
+        let mut late = mux.channel(2);
+        assert!(late.events.recv().await.is_none());
+        assert!(late.credit.is_closed());
+    }
+}
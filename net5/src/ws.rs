@@ -0,0 +1,206 @@
+use crate::auth::constant_time_eq;
+use crate::relay::Stream;
+use futures_util::{Sink, Stream as _};
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{header, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+fn to_io(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+/// Byte stream over binary WebSocket messages.
+///
+/// WebSocket has no half-close, so an empty message marks the end of one direction
+/// and the other one keeps flowing until it is shut down as well.
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    buf: Vec<u8>,
+    pos: usize,
+    read_closed: bool,
+    write_closed: bool,
+}
+
+impl<S> WsStream<S> {
+    fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            pos: 0,
+            read_closed: false,
+            write_closed: false,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.pos < self.buf.len() {
+                let len = out.remaining().min(self.buf.len() - self.pos);
+                out.put_slice(&self.buf[self.pos..self.pos + len]);
+                self.pos += len;
+
+                return Poll::Ready(Ok(()));
+            }
+
+            if self.read_closed {
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) if data.is_empty() => self.read_closed = true,
+                Some(Ok(Message::Binary(data))) => {
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                Some(Ok(Message::Close(_))) | None => self.read_closed = true,
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Err(to_io(err))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let mut inner = Pin::new(&mut self.inner);
+        ready!(inner.as_mut().poll_ready(cx)).map_err(to_io)?;
+        inner
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(to_io)?;
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(to_io)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.write_closed {
+            let mut inner = Pin::new(&mut self.inner);
+            ready!(inner.as_mut().poll_ready(cx)).map_err(to_io)?;
+            inner
+                .start_send(Message::Binary(Vec::new()))
+                .map_err(to_io)?;
+
+            self.write_closed = true;
+        }
+
+        self.poll_flush(cx)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for WsStream<S> {}
+
+/// Opens a tunnel to a `ws-remote` instance, `wss://` URLs go through TLS.
+pub async fn connect(
+    url: &str,
+    token: Option<&str>,
+) -> io::Result<WsStream<MaybeTlsStream<TcpStream>>> {
+    let mut request = url.into_client_request().map_err(to_io)?;
+
+    if let Some(token) = token {
+        let value = HeaderValue::from_str(&format!("Bearer {token}"))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        request.headers_mut().insert(header::AUTHORIZATION, value);
+    }
+
+    let (inner, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .map_err(to_io)?;
+
+    Ok(WsStream::new(inner))
+}
+
+/// Completes the server side of the upgrade, refusing requests without the expected token.
+pub async fn accept<S>(stream: S, token: Option<&str>) -> io::Result<WsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // The error type is dictated by tungstenite's callback signature.
+    #[allow(clippy::result_large_err)]
+    let check = |req: &Request, res: Response| {
+        let Some(token) = token else {
+            return Ok(res);
+        };
+
+        let expected = format!("Bearer {token}");
+        let found = req.headers().get(header::AUTHORIZATION);
+        if found.is_some_and(|found| constant_time_eq(found.as_bytes(), expected.as_bytes())) {
+            return Ok(res);
+        }
+
+        let mut res = ErrorResponse::new(None);
+        *res.status_mut() = StatusCode::UNAUTHORIZED;
+        Err(res)
+    };
+
+    let inner = tokio_tungstenite::accept_hdr_async(stream, check)
+        .await
+        .map_err(to_io)?;
+
+    Ok(WsStream::new(inner))
+}
+
+pub fn acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let mut reader = BufReader::new(File::open(key)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => break PrivateKey(key),
+            Some(_) => {}
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no private key in {}", key.display()),
+                ))
+            }
+        }
+    };
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}